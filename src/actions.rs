@@ -0,0 +1,205 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A change made to the homeserver, or that would be made in dry-run mode.
+#[derive(Debug, PartialEq, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    RegisterUser {
+        user_id: String,
+    },
+    CreateRoom {
+        alias: String,
+        is_space: bool,
+        join_rule: String,
+    },
+    JoinRoom {
+        room_id: String,
+    },
+    CreateAlias {
+        room_id: String,
+        alias: String,
+    },
+    SetCanonicalAlias {
+        room_id: String,
+        alias: String,
+    },
+    SetJoinRule {
+        room_id: String,
+        join_rule: String,
+        /// Rooms whose members can join a restricted room.
+        allow: Vec<String>,
+    },
+    SetParent {
+        room_id: String,
+        parent: String,
+    },
+    /// The parent is kept, as it may not be managed by us.
+    UnsetCanonicalParent {
+        room_id: String,
+        parent: String,
+    },
+    AddChild {
+        space_id: String,
+        child: String,
+    },
+    RemoveChild {
+        space_id: String,
+        child: String,
+    },
+    InviteUser {
+        room_id: String,
+        user_id: String,
+    },
+    ForceJoinUser {
+        room_id: String,
+        user_id: String,
+    },
+    KickUser {
+        room_id: String,
+        user_id: String,
+    },
+    SetPowerLevels {
+        room_id: String,
+        users: BTreeMap<String, i64>,
+    },
+    SetName {
+        room_id: String,
+        name: String,
+    },
+    SetTopic {
+        room_id: String,
+        topic: String,
+    },
+    SetAvatar {
+        room_id: String,
+        avatar: String,
+    },
+    SetHistoryVisibility {
+        room_id: String,
+        history_visibility: String,
+    },
+    SetGuestAccess {
+        room_id: String,
+        guest_access: String,
+    },
+    EnableEncryption {
+        room_id: String,
+    },
+    SetDirectoryVisibility {
+        room_id: String,
+        visibility: String,
+        /// Network of the appservice directory, if not the room directory.
+        network: Option<String>,
+    },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::RegisterUser { user_id } => write!(f, "Register user {}", user_id),
+            Action::CreateRoom {
+                alias,
+                is_space,
+                join_rule,
+            } => write!(
+                f,
+                "Create {} {} with join rule {}",
+                if *is_space { "space" } else { "room" },
+                alias,
+                join_rule
+            ),
+            Action::JoinRoom { room_id } => write!(f, "Join room {}", room_id),
+            Action::CreateAlias { room_id, alias } => {
+                write!(f, "Create alias {} for room {}", alias, room_id)
+            }
+            Action::SetCanonicalAlias { room_id, alias } => {
+                write!(f, "Set canonical alias of room {} to {}", room_id, alias)
+            }
+            Action::SetJoinRule {
+                room_id,
+                join_rule,
+                allow,
+            } => {
+                write!(f, "Set join rule of room {} to {}", room_id, join_rule)?;
+                if !allow.is_empty() {
+                    write!(f, " allowing members of {}", allow.join(", "))?;
+                }
+                Ok(())
+            }
+            Action::SetParent { room_id, parent } => {
+                write!(f, "Set parent of room {} to {}", room_id, parent)
+            }
+            Action::UnsetCanonicalParent { room_id, parent } => write!(
+                f,
+                "Make parent {} of room {} not canonical",
+                parent, room_id
+            ),
+            Action::AddChild { space_id, child } => {
+                write!(f, "Add child {} to space {}", child, space_id)
+            }
+            Action::RemoveChild { space_id, child } => {
+                write!(f, "Remove child {} from space {}", child, space_id)
+            }
+            Action::InviteUser { room_id, user_id } => {
+                write!(f, "Invite user {} to room {}", user_id, room_id)
+            }
+            Action::ForceJoinUser { room_id, user_id } => {
+                write!(f, "Force join user {} to room {}", user_id, room_id)
+            }
+            Action::KickUser { room_id, user_id } => {
+                write!(f, "Kick user {} from room {}", user_id, room_id)
+            }
+            Action::SetPowerLevels { room_id, users } => write!(
+                f,
+                "Set power levels of room {} to {}",
+                room_id,
+                users
+                    .iter()
+                    .map(|(user_id, level)| format!("{}={}", user_id, level))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Action::SetName { room_id, name } => {
+                write!(f, "Set name of room {} to {}", room_id, name)
+            }
+            Action::SetTopic { room_id, topic } => {
+                write!(f, "Set topic of room {} to {}", room_id, topic)
+            }
+            Action::SetAvatar { room_id, avatar } => {
+                write!(f, "Set avatar of room {} to {}", room_id, avatar)
+            }
+            Action::SetHistoryVisibility {
+                room_id,
+                history_visibility,
+            } => write!(
+                f,
+                "Set history visibility of room {} to {}",
+                room_id, history_visibility
+            ),
+            Action::SetGuestAccess {
+                room_id,
+                guest_access,
+            } => write!(
+                f,
+                "Set guest access of room {} to {}",
+                room_id, guest_access
+            ),
+            Action::EnableEncryption { room_id } => {
+                write!(f, "Enable encryption in room {}", room_id)
+            }
+            Action::SetDirectoryVisibility {
+                room_id,
+                visibility,
+                network,
+            } => write!(
+                f,
+                "Set visibility of room {} in the {} directory to {}",
+                room_id,
+                network.as_deref().unwrap_or("room"),
+                visibility
+            ),
+        }
+    }
+}
@@ -1,5 +1,6 @@
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result};
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
@@ -15,6 +16,11 @@ pub struct MatrixConfig {
     pub homeserver_url: String,
     pub mxid: String,
     pub password: String,
+    pub force_join: Option<bool>,
+    pub kick_reason: Option<String>,
+    pub protected_users: Option<Vec<String>>,
+    /// Token of the appservice owning the network directories rooms are published to.
+    pub appservice_token: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -32,18 +38,159 @@ pub struct LdapConfig {
     pub synapse_external_ids: Option<Vec<ExternalId>>,
 }
 
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GitAuthConfig {
+    pub username: Option<String>,
+    pub ssh_key: Option<PathBuf>,
+    pub ssh_key_passphrase: Option<String>,
+    pub ssh_agent: Option<bool>,
+    /// File containing the HTTPS token or password.
+    pub token_file: Option<PathBuf>,
+    /// Environment variable containing the HTTPS token or password.
+    pub token_env: Option<String>,
+    pub credential_helper: Option<bool>,
+    /// Only accept SSH host keys listed in this file.
+    pub known_hosts: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SignaturesConfig {
+    pub gpg_fingerprints: Option<Vec<String>>,
+    /// GnuPG home directory containing the public keys of `gpg_fingerprints`.
+    pub gpg_home: Option<PathBuf>,
+    /// File in the format expected by `ssh-keygen -Y verify`.
+    pub ssh_allowed_signers: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CommitterConfig {
+    pub name: String,
+    pub email: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GitSource {
+    pub url: String,
+    /// Branch, tag or commit to use, instead of the default branch.
+    #[serde(rename = "ref")]
+    pub git_ref: Option<String>,
+    pub auth: Option<GitAuthConfig>,
+    /// Refuse to use commits that are not signed by one of these keys.
+    pub verify_signatures: Option<SignaturesConfig>,
+    /// Commit the IDs of the rooms created for aliases to their files, and push them.
+    pub record_room_ids: Option<CommitterConfig>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub enum Source {
+    Git(GitSource),
+    /// A folder tree used as is, without cloning anything.
+    Local(PathBuf),
+}
+
+/// Pushes to the branch of the git source trigger a sync.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub listen: String,
+    pub secret: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Seconds between two syncs.
+    pub interval: Option<u64>,
+    pub webhook: Option<WebhookConfig>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum NameTransform {
+    DashesToSpaces,
+    UnderscoresToSpaces,
+    /// Upper case the first letter of every word.
+    TitleCase,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DefaultNamesConfig {
+    /// Applied in order to the folder name or alias localpart.
+    pub transforms: Vec<NameTransform>,
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     pub matrix: MatrixConfig,
     pub ldap: LdapConfig,
-    pub git_repository: String,
+    pub source: Source,
+    pub daemon: Option<DaemonConfig>,
+    /// Remember what was applied there, to skip syncs when nothing changed.
+    pub state_file: Option<PathBuf>,
+    /// Record the room of each space and room there, to keep them when they are moved. Needs a git
+    /// source and `state_file`. Relative paths are in the folder tree, and committed with the room
+    /// IDs, so they need `record_room_ids`.
+    pub lockfile: Option<PathBuf>,
+    /// Name spaces after their folder, and rooms after their alias, unless they have a name.
+    pub default_names: Option<DefaultNamesConfig>,
 }
 
 impl Config {
     pub fn load<P: AsRef<std::path::Path> + std::fmt::Debug>(path: P) -> Result<Self> {
         let contents = std::fs::read_to_string(path)?;
         let config: Self = serde_yaml::from_str(&contents)?;
+
+        let webhook = config.daemon.as_ref().and_then(|d| d.webhook.as_ref());
+        if webhook.is_some() && !matches!(config.source, Source::Git(_)) {
+            return Err(eyre!("Webhooks can only be used with a git source"));
+        }
+        // Anyone could sign webhooks with an empty secret
+        if webhook.map_or(false, |w| w.secret.is_empty()) {
+            return Err(eyre!("The webhook secret can't be empty"));
+        }
+
+        if let Some(lockfile) = &config.lockfile {
+            // Moves are found by diffing against the commit of the last sync
+            let git_source = match &config.source {
+                Source::Git(git_source) if config.state_file.is_some() => git_source,
+                _ => {
+                    return Err(eyre!(
+                        "A lockfile can only be used with a git source and a state file"
+                    ))
+                }
+            };
+            // The clone is thrown away, or reset, after each sync
+            if lockfile.is_relative() && git_source.record_room_ids.is_none() {
+                return Err(eyre!(
+                    "A lockfile in the folder tree needs record_room_ids to be committed"
+                ));
+            }
+        }
+
+        if let Source::Git(git_source) = &config.source {
+            if git_source.record_room_ids.is_some() {
+                if git_source.verify_signatures.is_some() {
+                    return Err(eyre!(
+                        "Can't record room IDs in unsigned commits when signatures are verified"
+                    ));
+                }
+                // Whether other refs are branches is checked once cloned
+                let is_commit = git_source.git_ref.as_ref().map_or(false, |r| {
+                    r.len() == 40 && r.chars().all(|c| c.is_ascii_hexdigit())
+                });
+                if is_commit {
+                    return Err(eyre!("Can't record room IDs as the ref is not a branch"));
+                }
+            }
+        }
+
         Ok(config)
     }
 }
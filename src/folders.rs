@@ -1,6 +1,10 @@
 use crate::config;
+use crate::git::ChangedFile;
 use crate::ldap::LdapClient;
+use crate::lock::Lock;
 use crate::matrix::MatrixClient;
+use crate::report::{RoomReport, SpaceReport};
+use crate::state::Memberships;
 use async_recursion::async_recursion;
 use color_eyre::eyre::{eyre, Result};
 use matrix_sdk::ruma::UserId;
@@ -8,8 +12,9 @@ use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::collections::HashSet;
 use std::convert::TryFrom;
+use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tracing::*;
 
 #[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Clone)]
@@ -33,19 +38,149 @@ struct LdapGroupMetadata {
     power_level: i32,
 }
 
+/// Who can join a room, as set in `m.room.join_rules`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum JoinRule {
+    Public,
+    #[serde(alias = "private")]
+    Invite,
+    Knock,
+    /// Members of the parent space or of the allowed rooms can join.
+    Restricted,
+    /// Members of the parent space or of the allowed rooms can join, and others can knock.
+    KnockRestricted,
+}
+
+impl Default for JoinRule {
+    fn default() -> Self {
+        JoinRule::Invite
+    }
+}
+
+impl JoinRule {
+    pub fn is_restricted(&self) -> bool {
+        matches!(self, JoinRule::Restricted | JoinRule::KnockRestricted)
+    }
+
+    /// The first room version supporting the join rule.
+    pub fn min_room_version(&self) -> Option<u32> {
+        match self {
+            JoinRule::Public | JoinRule::Invite => None,
+            JoinRule::Knock => Some(7),
+            JoinRule::Restricted => Some(8),
+            JoinRule::KnockRestricted => Some(10),
+        }
+    }
+}
+
+impl fmt::Display for JoinRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            JoinRule::Public => "public",
+            JoinRule::Invite => "invite",
+            JoinRule::Knock => "knock",
+            JoinRule::Restricted => "restricted",
+            JoinRule::KnockRestricted => "knock_restricted",
+        })
+    }
+}
+
+/// Who can read the history of a room, as set in `m.room.history_visibility`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryVisibility {
+    WorldReadable,
+    Shared,
+    Invited,
+    Joined,
+}
+
+impl fmt::Display for HistoryVisibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HistoryVisibility::WorldReadable => "world_readable",
+            HistoryVisibility::Shared => "shared",
+            HistoryVisibility::Invited => "invited",
+            HistoryVisibility::Joined => "joined",
+        })
+    }
+}
+
+/// Whether guests can join a room, as set in `m.room.guest_access`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum GuestAccess {
+    CanJoin,
+    Forbidden,
+}
+
+impl fmt::Display for GuestAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            GuestAccess::CanJoin => "can_join",
+            GuestAccess::Forbidden => "forbidden",
+        })
+    }
+}
+
+/// Whether a room is published in the room directory.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum DirectoryVisibility {
+    Public,
+    Private,
+}
+
+impl fmt::Display for DirectoryVisibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DirectoryVisibility::Public => "public",
+            DirectoryVisibility::Private => "private",
+        })
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
 #[serde(default)]
 pub struct RoomMetadata {
     pub id: Option<String>,
     pub alias: Option<String>,
-    visibility: String,
+    name: Option<String>,
+    topic: Option<String>,
+    /// Image uploaded as the avatar of the room, relative to the folder of the room.
+    avatar: Option<PathBuf>,
+    // Previously named visibility
+    #[serde(alias = "visibility")]
+    join_rule: JoinRule,
+    /// IDs or aliases of the rooms whose members can join a restricted room, besides the ones of
+    /// its parent space.
+    allowed_rooms: Vec<String>,
+    history_visibility: Option<HistoryVisibility>,
+    guest_access: Option<GuestAccess>,
+    /// Encryption can only be enabled, rooms stay encrypted otherwise.
+    encryption: Option<bool>,
+    directory: Option<DirectoryVisibility>,
+    /// Network of the appservice whose directory the room is also published to.
+    directory_network: Option<String>,
     ldap_groups: Vec<LdapGroupMetadata>,
     users: HashSet<UserMetadata>,
     is_space: bool,
+    #[serde(skip)]
+    ldap_error: Option<String>,
+    /// Path of the file or folder defining the room, relative to the root of the tree.
+    #[serde(skip)]
+    path: PathBuf,
+    /// Path of the avatar on disk.
+    #[serde(skip)]
+    avatar_file: Option<PathBuf>,
+    #[serde(skip)]
+    avatar_error: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct SpaceFolder {
+    path: PathBuf,
     metadata: Option<RoomMetadata>,
     pub rooms: Vec<RoomMetadata>,
     children: Vec<Box<SpaceFolder>>,
@@ -104,17 +239,102 @@ impl LdapGroupMetadata {
 }
 
 impl RoomMetadata {
-    // It is actually used to get default values for metadatas
-    #[allow(dead_code)]
-    fn default() -> Self {
-        let default = std::default::Default::default();
-        Self {
-            visibility: "private".to_string(),
-            ..default
+    /// Fetch the users of the room from its LDAP groups. Failures are recorded in the metadata so
+    /// that the membership of the room is left untouched.
+    async fn populate_users(
+        &mut self,
+        ldap_client: &mut LdapClient,
+        localpart_template: &str,
+        mx_server_name: &str,
+        synapse_external_ids: Option<&Vec<config::ExternalId>>,
+    ) {
+        info!(
+            "Fetching users for room {} {}",
+            self.id.as_ref().unwrap_or(&String::new()),
+            self.alias.as_ref().unwrap_or(&String::new())
+        );
+
+        for group in &self.ldap_groups {
+            match group
+                .get_users_metadatas_for_group(
+                    ldap_client,
+                    localpart_template,
+                    mx_server_name,
+                    synapse_external_ids,
+                )
+                .await
+            {
+                Ok(users) => self.users.extend(users),
+                Err(e) => {
+                    let e = format!("Failed to fetch users of group {}: {:#}", group.dn, e);
+                    error!(
+                        "{} {}: {}",
+                        self.id.as_ref().unwrap_or(&String::new()),
+                        self.alias.as_ref().unwrap_or(&String::new()),
+                        e
+                    );
+                    self.ldap_error = Some(e);
+                }
+            }
+        }
+    }
+
+    /// Find the avatar on disk. Failures are recorded in the metadata so that the rest of the
+    /// room is still reconciled.
+    fn load_avatar(&mut self, base: &Path, folder: &Path) {
+        if let Some(avatar) = &self.avatar {
+            match avatar_file(base, folder, avatar) {
+                Ok(file) => self.avatar_file = Some(file),
+                Err(e) => {
+                    let e = format!("{:#}", e);
+                    error!("{}: {}", self.path.display(), e);
+                    self.avatar_error = Some(e);
+                }
+            }
+        }
+    }
+
+    /// Path of the avatar relative to the root of the tree.
+    fn avatar_path(&self) -> Option<PathBuf> {
+        let folder = if self.is_space {
+            self.path.as_path()
+        } else {
+            self.path.parent()?
+        };
+        self.avatar.as_ref().map(|avatar| folder.join(avatar))
+    }
+
+    /// The power level of each user of the room. Users in several groups get the highest of their
+    /// levels.
+    fn power_levels(&self) -> BTreeMap<String, i32> {
+        let mut levels = BTreeMap::new();
+        for user in &self.users {
+            let level = levels.entry(user.mxid.clone()).or_insert(user.power_level);
+            *level = (*level).max(user.power_level);
         }
+        levels
     }
 
-    async fn ensure(&self, parent: Option<&str>, matrix_client: &MatrixClient) -> Result<String> {
+    /// The users of the room, sorted so that they can be compared between syncs.
+    fn membership(&self) -> Option<Vec<(String, i32)>> {
+        if self.ldap_error.is_some() {
+            return None;
+        }
+        let mut users: Vec<(String, i32)> = self
+            .users
+            .iter()
+            .map(|u| (u.mxid.clone(), u.power_level))
+            .collect();
+        users.sort();
+        Some(users)
+    }
+
+    async fn ensure(
+        &self,
+        parent_space: Option<&str>,
+        matrix_client: &MatrixClient,
+        report: &mut RoomReport,
+    ) -> Result<String> {
         info!(
             "Processing room {} {}",
             self.id.as_ref().unwrap_or(&String::new()),
@@ -125,39 +345,72 @@ impl RoomMetadata {
             .ensure_room(
                 self.id.as_ref(),
                 self.alias.as_ref(),
-                &self.visibility,
+                self.join_rule,
+                &self.allowed_rooms,
                 self.is_space,
-                parent,
+                parent_space,
+            )
+            .await?;
+
+        // A broken avatar must not keep the users of the room from being reconciled
+        if let Err(e) = matrix_client
+            .ensure_room_profile(
+                &room_id,
+                self.name.as_ref(),
+                self.topic.as_ref(),
+                self.avatar_file.as_deref(),
+            )
+            .await
+        {
+            let e = format!("Failed to set the profile of room {}: {:#}", room_id, e);
+            error!("{}", e);
+            report.record_error(Some(&e));
+        }
+
+        matrix_client
+            .ensure_room_settings(
+                &room_id,
+                self.history_visibility,
+                self.guest_access,
+                self.encryption,
             )
             .await?;
 
+        if let Some(directory) = self.directory {
+            matrix_client
+                .ensure_room_directory(&room_id, directory, self.directory_network.as_deref())
+                .await?;
+        }
+
+        if self.ldap_error.is_some() {
+            warn!(
+                "Not processing users for room {} as they could not be fetched",
+                room_id
+            );
+            return Ok(room_id);
+        }
+
         info!("Processing users for room {}", room_id);
         // Find what users we need to add, remove, and update the power level
         let current_users = matrix_client.get_room_members(&room_id).await?;
+        let power_levels = self.power_levels();
         let mut users_to_add = HashSet::new();
         let mut users_to_remove = HashSet::new();
 
-        for user in &self.users {
-            if !current_users.contains(&user.mxid) {
-                users_to_add.insert(user);
+        for mxid in power_levels.keys() {
+            if matrix_client.needs_adding(current_users.get(mxid).map(String::as_str)) {
+                users_to_add.insert(mxid);
             }
         }
 
-        for user_id in &current_users {
-            if !(&self.users)
-                .iter()
-                .map(|e| e.mxid.clone())
-                .collect::<HashSet<String>>()
-                .contains(user_id)
-            {
+        for user_id in current_users.keys() {
+            if !power_levels.contains_key(user_id) && !matrix_client.is_protected(user_id) {
                 users_to_remove.insert(user_id);
             }
         }
 
-        for user in &users_to_add {
-            matrix_client
-                .add_user_to_room(&room_id, &user.mxid, user.power_level)
-                .await?;
+        for user_id in &users_to_add {
+            matrix_client.add_user_to_room(&room_id, user_id).await?;
         }
         for user_id in &users_to_remove {
             matrix_client
@@ -166,11 +419,9 @@ impl RoomMetadata {
         }
 
         info!("Processing power levels for room {}", room_id);
-        for user in &self.users {
-            matrix_client
-                .ensure_user_powerlevel(&room_id, &user.mxid, user.power_level)
-                .await?;
-        }
+        matrix_client
+            .ensure_users_powerlevel(&room_id, &power_levels)
+            .await?;
 
         info!("Finished processing room {}", room_id);
         Ok(room_id.clone())
@@ -178,9 +429,16 @@ impl RoomMetadata {
 }
 
 impl SpaceFolder {
-    fn new_rec(root: &Path) -> Result<Box<Self>> {
+    fn new_rec(
+        base: &Path,
+        root: &Path,
+        lock: &Lock,
+        default_names: Option<&config::DefaultNamesConfig>,
+    ) -> Result<Box<Self>> {
         debug!("Starting to process folder at {}", root.display());
+        let path = root.strip_prefix(base)?.to_path_buf();
         let mut space_folder = Box::new(Self {
+            path: path.clone(),
             metadata: None,
             rooms: vec![],
             children: vec![],
@@ -193,6 +451,17 @@ impl SpaceFolder {
                         let contents = std::fs::read_to_string(entry.path())?;
                         let mut metadata: RoomMetadata = serde_yaml::from_str(&contents)?;
                         metadata.is_space = true;
+                        metadata.path = path.clone();
+                        metadata.load_avatar(base, root);
+                        if metadata.id.is_none() {
+                            metadata.id = lock.room_id(&path).cloned();
+                        }
+                        if let (None, Some(default_names)) = (&metadata.name, default_names) {
+                            metadata.name = root
+                                .file_name()
+                                .and_then(|n| n.to_str())
+                                .map(|n| default_name(n, default_names));
+                        }
                         space_folder.metadata = Some(metadata);
                     } else if file_name.starts_with('!') || file_name.starts_with('#') {
                         let contents = std::fs::read_to_string(entry.path())?;
@@ -202,15 +471,30 @@ impl SpaceFolder {
                         } else {
                             metadata.alias = Some(file_name.to_string());
                         }
+                        metadata.path = entry.path().strip_prefix(base)?.to_path_buf();
+                        metadata.load_avatar(base, root);
+                        if metadata.id.is_none() {
+                            metadata.id = lock.room_id(&metadata.path).cloned();
+                        }
+                        if let (None, Some(default_names)) = (&metadata.name, default_names) {
+                            metadata.name = metadata
+                                .alias
+                                .as_ref()
+                                .and_then(|a| a.trim_start_matches('#').split(':').next())
+                                .map(|localpart| default_name(localpart, default_names));
+                        }
                         space_folder.rooms.push(metadata);
                     } else {
                         info!("Unsupported file found at {}", entry.path().display());
                     }
                 }
             } else if entry.file_type()?.is_dir() {
-                space_folder
-                    .children
-                    .push(SpaceFolder::new_rec(entry.path().as_path())?);
+                space_folder.children.push(SpaceFolder::new_rec(
+                    base,
+                    entry.path().as_path(),
+                    lock,
+                    default_names,
+                )?);
             } else if entry.file_type()?.is_symlink() {
                 info!(
                     "Symlinks are not supported (yet), found at {}",
@@ -222,14 +506,22 @@ impl SpaceFolder {
         Ok(space_folder)
     }
 
-    pub fn new(root: &Path) -> Result<Vec<Box<Self>>> {
+    /// Build the trees of the folders in `root`. Spaces and rooms without an ID get the one
+    /// recorded for them in the lock, if any, and those without a name get a default one if
+    /// `default_names` is set.
+    pub fn new(
+        root: &Path,
+        lock: &Lock,
+        default_names: Option<&config::DefaultNamesConfig>,
+    ) -> Result<Vec<Box<Self>>> {
         info!("Starting to process folder at {}", root.display());
 
         let mut space_folders = vec![];
 
         for entry in walkdir(root)? {
             if entry.file_type()?.is_dir() {
-                let folder = SpaceFolder::new_rec(entry.path().as_path())?;
+                let folder =
+                    SpaceFolder::new_rec(root, entry.path().as_path(), lock, default_names)?;
                 space_folders.push(folder);
             } else {
                 info!(
@@ -245,6 +537,10 @@ impl SpaceFolder {
     }
 
     pub fn check(&self) -> Result<()> {
+        self.check_rec(true)
+    }
+
+    fn check_rec(&self, top_level: bool) -> Result<()> {
         if self.metadata.is_none() {
             return Err(eyre!("Folder should contain a metadata.yml"));
         }
@@ -265,6 +561,32 @@ impl SpaceFolder {
             check_user(&user.mxid);
         }
 
+        // Only top-level spaces have no parent space whose members could join them
+        let metadata = self.metadata.as_ref().unwrap();
+        if top_level && metadata.join_rule.is_restricted() && metadata.allowed_rooms.is_empty() {
+            return Err(eyre!(
+                "{} has the join rule {} but neither a parent space nor allowed rooms",
+                metadata.path.display(),
+                metadata.join_rule
+            ));
+        }
+
+        for room in self.metadata.iter().chain(self.rooms.iter()) {
+            if room.directory_network.is_some() && room.directory.is_none() {
+                return Err(eyre!(
+                    "{} has a directory network but no directory visibility",
+                    room.path.display()
+                ));
+            }
+            if !room.allowed_rooms.is_empty() && !room.join_rule.is_restricted() {
+                return Err(eyre!(
+                    "{} has allowed rooms but its join rule {} is not restricted",
+                    room.path.display(),
+                    room.join_rule
+                ));
+            }
+        }
+
         for room in &self.rooms {
             for user in &room.users {
                 check_user(&user.mxid);
@@ -272,7 +594,7 @@ impl SpaceFolder {
         }
 
         for child in &self.children {
-            child.check()?;
+            child.check_rec(false)?;
         }
 
         Ok(())
@@ -285,56 +607,26 @@ impl SpaceFolder {
         localpart_template: &str,
         mx_server_name: &str,
         synapse_external_ids: Option<&'async_recursion Vec<config::ExternalId>>,
-    ) -> Result<()> {
-        info!(
-            "Fetching users for room {} {}",
-            self.metadata
-                .as_ref()
-                .unwrap()
-                .id
-                .as_ref()
-                .unwrap_or(&String::new()),
-            self.metadata
-                .as_ref()
-                .unwrap()
-                .alias
-                .as_ref()
-                .unwrap_or(&String::new())
-        );
-
-        let mut users = HashSet::new();
-        for group in &self.metadata.as_ref().unwrap().ldap_groups {
-            users.extend(
-                group
-                    .get_users_metadatas_for_group(
-                        ldap_client,
-                        localpart_template,
-                        mx_server_name,
-                        synapse_external_ids,
-                    )
-                    .await?,
-            );
-        }
-        self.metadata.as_mut().unwrap().users.extend(users);
+    ) {
+        self.metadata
+            .as_mut()
+            .unwrap()
+            .populate_users(
+                ldap_client,
+                localpart_template,
+                mx_server_name,
+                synapse_external_ids,
+            )
+            .await;
 
         for room in &mut self.rooms {
-            info!(
-                "Fetching users for room {} {}",
-                room.id.as_ref().unwrap_or(&String::new()),
-                room.alias.as_ref().unwrap_or(&String::new())
-            );
-            for group in &room.ldap_groups {
-                room.users.extend(
-                    group
-                        .get_users_metadatas_for_group(
-                            ldap_client,
-                            localpart_template,
-                            mx_server_name,
-                            synapse_external_ids,
-                        )
-                        .await?,
-                );
-            }
+            room.populate_users(
+                ldap_client,
+                localpart_template,
+                mx_server_name,
+                synapse_external_ids,
+            )
+            .await;
         }
 
         for child in &mut self.children {
@@ -345,32 +637,99 @@ impl SpaceFolder {
                     mx_server_name,
                     synapse_external_ids,
                 )
-                .await?;
+                .await;
         }
-
-        Ok(())
     }
 
-    pub fn get_all_users(&self) -> HashSet<UserMetadata> {
-        let users = self.metadata.as_ref().unwrap().users.clone();
-
-        let users = self.rooms.iter().fold(users, |mut acc, room| {
-            acc.extend(room.users.clone());
-            acc
-        });
+    /// Get the users of all the selected rooms of the tree, or of all of them if `selected` is None.
+    pub fn get_all_users(&self, selected: Option<&HashSet<PathBuf>>) -> HashSet<UserMetadata> {
+        let users = self
+            .metadata
+            .iter()
+            .chain(self.rooms.iter())
+            .filter(|room| selected.map_or(true, |s| s.contains(&room.path)))
+            .fold(HashSet::new(), |mut acc, room| {
+                acc.extend(room.users.clone());
+                acc
+            });
 
         self.children.iter().fold(users, |mut acc, child| {
-            acc.extend(child.get_all_users());
+            acc.extend(child.get_all_users(selected));
             acc
         })
     }
 
+    /// Select the spaces and rooms whose avatar changed.
+    pub fn select_changed_avatars(
+        &self,
+        changed_files: &[ChangedFile],
+        selected: &mut HashSet<PathBuf>,
+    ) {
+        for room in self.metadata.iter().chain(self.rooms.iter()) {
+            if let Some(avatar) = room.avatar_path() {
+                if changed_files.iter().any(|file| file.path == avatar) {
+                    selected.insert(room.path.clone());
+                }
+            }
+        }
+        for child in &self.children {
+            child.select_changed_avatars(changed_files, selected);
+        }
+    }
+
+    /// Whether this space, or any of its rooms or subfolders, is selected.
+    pub fn needs_sync(&self, selected: &HashSet<PathBuf>) -> bool {
+        selected.contains(&self.path)
+            || self.rooms.iter().any(|room| selected.contains(&room.path))
+            || self.children.iter().any(|child| child.needs_sync(selected))
+    }
+
+    /// Collect the users of all the rooms of the tree.
+    pub fn memberships(&self, memberships: &mut Memberships) {
+        for room in self.metadata.iter().chain(self.rooms.iter()) {
+            memberships.insert(room.path.clone(), room.membership());
+        }
+        for child in &self.children {
+            child.memberships(memberships);
+        }
+    }
+
+    /// The parent space of every space and room of the tree, by path. Rooms are children of the
+    /// space of their own folder, and subspaces of the space of the enclosing folder.
+    pub fn parents(&self) -> BTreeMap<PathBuf, Option<PathBuf>> {
+        let mut parents = BTreeMap::new();
+        self.collect_parents(None, &mut parents);
+        parents
+    }
+
+    fn collect_parents(
+        &self,
+        parent: Option<&Path>,
+        parents: &mut BTreeMap<PathBuf, Option<PathBuf>>,
+    ) {
+        parents.insert(self.path.clone(), parent.map(Path::to_path_buf));
+        for room in &self.rooms {
+            parents.insert(room.path.clone(), Some(self.path.clone()));
+        }
+        for child in &self.children {
+            child.collect_parents(Some(&self.path), parents);
+        }
+    }
+
+    /// Reconcile the tree, or only its selected spaces and rooms if `selected` is set. The spaces
+    /// containing selected nodes are only looked up, to link them to their children.
+    ///
+    /// `parents` are the parents computed by [`SpaceFolder::parents`] on the top-level folder, and
+    /// `room_ids` the IDs of the spaces processed so far, by path.
     #[async_recursion]
     pub async fn folders_to_matrix(
         &self,
         matrix_client: &MatrixClient,
-        parent: Option<&'async_recursion str>,
-    ) -> Result<()> {
+        parents: &'async_recursion BTreeMap<PathBuf, Option<PathBuf>>,
+        room_ids: &mut BTreeMap<PathBuf, String>,
+        selected: Option<&'async_recursion HashSet<PathBuf>>,
+        report: &mut SpaceReport,
+    ) -> Result<String> {
         info!(
             "Processing space {} {}",
             self.metadata
@@ -387,23 +746,336 @@ impl SpaceFolder {
                 .unwrap_or(&String::new())
         );
 
-        let room_id = self
-            .metadata
-            .as_ref()
-            .unwrap()
-            .ensure(parent, matrix_client)
-            .await?;
+        let is_selected = |path: &PathBuf| selected.map_or(true, |s| s.contains(path));
+        let space_selected = is_selected(&self.path);
+        let parent_id = |path: &PathBuf, room_ids: &BTreeMap<PathBuf, String>| {
+            parents
+                .get(path)
+                .and_then(Option::as_ref)
+                .and_then(|parent| room_ids.get(parent))
+                .cloned()
+        };
+
+        let metadata = self.metadata.as_ref().unwrap();
+        report.space = RoomReport::new(&self.path, metadata.id.as_ref(), metadata.alias.as_ref());
+        let space_id = if space_selected {
+            let parent_space = parent_id(&self.path, room_ids);
+            metadata
+                .ensure(parent_space.as_deref(), matrix_client, &mut report.space)
+                .await
+        } else {
+            matrix_client
+                .find_room(metadata.id.as_ref(), metadata.alias.as_ref())
+                .await
+        };
+        report.space.record(&space_id, matrix_client.take_actions());
+        report.space.record_error(metadata.ldap_error.as_ref());
+        report.space.record_error(metadata.avatar_error.as_ref());
+        let space_id = match space_id {
+            Ok(space_id) => space_id,
+            Err(e) => {
+                error!("Skipping rooms and subfolders of the space: {:#}", e);
+                return Err(e);
+            }
+        };
+        room_ids.insert(self.path.clone(), space_id.clone());
+
+        // Rooms and subfolders of this folder are children of its own space, not of its parent
+        let mut children_ids = vec![];
+        // If some children could not be processed, we don't know all of them
+        let mut all_children = true;
 
         for room in &self.rooms {
-            let _room_id = room.ensure(parent, matrix_client).await?;
+            let room_selected = is_selected(&room.path);
+            // Unselected rooms are only needed to know all the children of a selected space
+            if !room_selected && !space_selected {
+                continue;
+            }
+
+            let mut room_report =
+                RoomReport::new(&room.path, room.id.as_ref(), room.alias.as_ref());
+            let room_id = if room_selected {
+                let parent_space = parent_id(&room.path, room_ids);
+                room.ensure(parent_space.as_deref(), matrix_client, &mut room_report)
+                    .await
+            } else {
+                matrix_client
+                    .find_room(room.id.as_ref(), room.alias.as_ref())
+                    .await
+            };
+            room_report.record(&room_id, matrix_client.take_actions());
+            room_report.record_error(room.ldap_error.as_ref());
+            room_report.record_error(room.avatar_error.as_ref());
+            if room_selected || room_report.error.is_some() {
+                report.rooms.push(room_report);
+            }
+            match room_id {
+                Ok(room_id) => children_ids.push(room_id),
+                Err(e) => {
+                    error!("{:#}", e);
+                    all_children = false;
+                }
+            }
         }
 
         for child in &self.children {
-            child
-                .folders_to_matrix(matrix_client, Some(&room_id))
-                .await?;
+            let child_selected = selected.map_or(true, |s| child.needs_sync(s));
+            if !child_selected && !space_selected {
+                continue;
+            }
+
+            let mut child_report = SpaceReport::default();
+            let child_id = if child_selected {
+                child
+                    .folders_to_matrix(
+                        matrix_client,
+                        parents,
+                        room_ids,
+                        selected,
+                        &mut child_report,
+                    )
+                    .await
+            } else {
+                let metadata = child.metadata.as_ref().unwrap();
+                child_report.space =
+                    RoomReport::new(&child.path, metadata.id.as_ref(), metadata.alias.as_ref());
+                let child_id = matrix_client
+                    .find_room(metadata.id.as_ref(), metadata.alias.as_ref())
+                    .await;
+                child_report.space.record(&child_id, vec![]);
+                if let Err(e) = &child_id {
+                    error!("{:#}", e);
+                }
+                child_id
+            };
+            if child_selected || child_report.space.error.is_some() {
+                report.children.push(child_report);
+            }
+            match child_id {
+                Ok(child_id) => children_ids.push(child_id),
+                Err(_) => all_children = false,
+            }
         }
 
-        Ok(())
+        if !space_selected {
+            return Ok(space_id);
+        }
+
+        info!("Processing children for space {}", space_id);
+        let result = matrix_client
+            .ensure_space_children(&space_id, &children_ids, all_children)
+            .await;
+        report.space.actions.extend(matrix_client.take_actions());
+        if let Err(e) = result {
+            error!("{:#}", e);
+            report.space.error = Some(format!("{:#}", e));
+        }
+
+        Ok(space_id)
+    }
+}
+
+/// Content type of an avatar, if it is an image format supported by clients.
+pub fn avatar_content_type(avatar: &Path) -> Option<&'static str> {
+    match avatar
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase)
+        .as_deref()
+    {
+        Some("png") => Some("image/png"),
+        Some("jpg") | Some("jpeg") => Some("image/jpeg"),
+        Some("gif") => Some("image/gif"),
+        Some("webp") => Some("image/webp"),
+        Some("svg") => Some("image/svg+xml"),
+        _ => None,
+    }
+}
+
+/// Find the avatar of a room on disk, making sure that it is a file of the tree.
+fn avatar_file(base: &Path, folder: &Path, avatar: &Path) -> Result<PathBuf> {
+    let file = folder.join(avatar);
+    let metadata = fs::symlink_metadata(&file)
+        .map_err(|e| eyre!("Could not read avatar {}: {}", file.display(), e))?;
+    if metadata.file_type().is_symlink() {
+        return Err(eyre!("Avatar {} is a symlink", file.display()));
+    }
+    if avatar_content_type(&file).is_none() {
+        return Err(eyre!("Unsupported avatar format {}", file.display()));
+    }
+    let canonical = file.canonicalize()?;
+    if !canonical.starts_with(base.canonicalize()?) {
+        return Err(eyre!("Avatar {} is outside of the tree", file.display()));
+    }
+
+    Ok(canonical)
+}
+
+/// Derive a name from a folder name or alias localpart.
+fn default_name(source: &str, config: &config::DefaultNamesConfig) -> String {
+    config
+        .transforms
+        .iter()
+        .fold(source.to_string(), |name, transform| match transform {
+            config::NameTransform::DashesToSpaces => name.replace('-', " "),
+            config::NameTransform::UnderscoresToSpaces => name.replace('_', " "),
+            config::NameTransform::TitleCase => name
+                .split(' ')
+                .map(|word| {
+                    let mut chars = word.chars();
+                    match chars.next() {
+                        Some(first) => first.to_uppercase().chain(chars).collect(),
+                        None => String::new(),
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join(" "),
+        })
+}
+
+/// Get the path of the space or room defined by a file.
+pub fn node_path(file: &Path) -> Option<&Path> {
+    match file.file_name().and_then(|n| n.to_str()) {
+        Some("metadata.yml") | Some("metadata.yaml") => file.parent(),
+        _ => Some(file),
+    }
+}
+
+/// Select the spaces and rooms defined by changed files. Adding or removing one also changes the
+/// children of the space containing it.
+pub fn select_changed_files(changed_files: &[ChangedFile], selected: &mut HashSet<PathBuf>) {
+    for file in changed_files {
+        if let Some(node) = node_path(&file.path) {
+            selected.insert(node.to_path_buf());
+            if file.added_or_removed {
+                if let Some(parent) = node.parent() {
+                    selected.insert(parent.to_path_buf());
+                }
+            }
+        }
+    }
+}
+
+/// Record the ID of a room in the file defining it, so that it is found even if its alias changes.
+/// Returns the path of the file, relative to `root`, or None if it already has an ID or the ID
+/// can't be added to it.
+pub fn record_room_id(root: &Path, path: &Path, room_id: &str) -> Result<Option<PathBuf>> {
+    let file = if root.join(path).is_dir() {
+        ["metadata.yml", "metadata.yaml"]
+            .iter()
+            .map(|name| path.join(name))
+            .find(|file| root.join(file).is_file())
+            .ok_or_else(|| eyre!("No metadata file found in {}", path.display()))?
+    } else {
+        path.to_path_buf()
+    };
+    // Rooms named after their ID already have it
+    if file
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map_or(false, |n| n.starts_with('!'))
+    {
+        return Ok(None);
+    }
+
+    // Append to the file rather than serializing the metadata again, to keep comments
+    let mut contents = std::fs::read_to_string(root.join(&file))?;
+    let metadata: RoomMetadata = serde_yaml::from_str(&contents)?;
+    if metadata.id.is_some() {
+        return Ok(None);
+    }
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents.push_str(&format!("id: '{}'\n", room_id));
+
+    // Appending only works for block mappings, don't break flow mappings such as `{}`
+    match serde_yaml::from_str::<RoomMetadata>(&contents) {
+        Ok(metadata) if metadata.id.as_deref() == Some(room_id) => (),
+        _ => {
+            warn!(
+                "Can't add the room ID {} to {}, only the lockfile, if any, has it",
+                room_id,
+                file.display()
+            );
+            return Ok(None);
+        }
+    }
+    std::fs::write(root.join(&file), contents)?;
+
+    Ok(Some(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn parents() {
+        let dir = TempDir::new("spacehub").unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("top/sub")).unwrap();
+        fs::write(root.join("top/metadata.yml"), "alias: '#top:example.org'\n").unwrap();
+        fs::write(root.join("top/#top-room:example.org"), "name: Top room\n").unwrap();
+        fs::write(
+            root.join("top/sub/metadata.yml"),
+            "alias: '#sub:example.org'\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("top/sub/#sub-room:example.org"),
+            "name: Sub room\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("top/sub/!room:example.org"),
+            "alias: '#other:example.org'\n",
+        )
+        .unwrap();
+
+        let folders = SpaceFolder::new(root, &Lock::default(), None).unwrap();
+        assert_eq!(folders.len(), 1);
+
+        let expected: BTreeMap<PathBuf, Option<PathBuf>> = vec![
+            ("top", None),
+            ("top/#top-room:example.org", Some("top")),
+            ("top/sub", Some("top")),
+            ("top/sub/#sub-room:example.org", Some("top/sub")),
+            ("top/sub/!room:example.org", Some("top/sub")),
+        ]
+        .into_iter()
+        .map(|(path, parent)| (PathBuf::from(path), parent.map(PathBuf::from)))
+        .collect();
+        assert_eq!(folders[0].parents(), expected);
+    }
+
+    #[test]
+    fn invalid_avatars() {
+        let dir = TempDir::new("spacehub").unwrap();
+        let root = dir.path().join("tree");
+        fs::create_dir_all(root.join("top")).unwrap();
+        fs::write(dir.path().join("secret.png"), "secret").unwrap();
+        fs::write(root.join("top/avatar.png"), "avatar").unwrap();
+        fs::write(root.join("top/avatar.txt"), "avatar").unwrap();
+        let avatar_error = |avatar: &str| {
+            fs::write(
+                root.join("top/metadata.yml"),
+                format!("alias: '#top:example.org'\navatar: '{}'\n", avatar),
+            )
+            .unwrap();
+            // Invalid avatars don't keep the rest of the tree from being reconciled
+            let folders = SpaceFolder::new(&root, &Lock::default(), None).unwrap();
+            folders[0].metadata.as_ref().unwrap().avatar_error.clone()
+        };
+
+        assert_eq!(avatar_error("avatar.png"), None);
+        assert!(avatar_error("avatar.txt").is_some());
+        assert!(avatar_error("missing.png").is_some());
+        assert!(avatar_error("../../secret.png").is_some());
+        assert!(avatar_error(dir.path().join("secret.png").to_str().unwrap()).is_some());
+        std::os::unix::fs::symlink(dir.path().join("secret.png"), root.join("top/link.png"))
+            .unwrap();
+        assert!(avatar_error("link.png").is_some());
     }
 }
@@ -0,0 +1,493 @@
+use crate::config::{CommitterConfig, GitAuthConfig, GitSource, SignaturesConfig};
+use color_eyre::eyre::{eyre, Result};
+use git2::{
+    build::RepoBuilder, Cred, CredentialType, Delta, DiffFindOptions, FetchOptions, Oid,
+    PushOptions, RemoteCallbacks, Repository, ResetType, Signature,
+};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempdir::TempDir;
+use tracing::*;
+
+/// A file changed between two commits.
+pub struct ChangedFile {
+    /// Path relative to the root of the repository.
+    pub path: PathBuf,
+    /// Whether the file was added or removed rather than modified.
+    pub added_or_removed: bool,
+    /// Where the file was before, if it has been moved.
+    pub moved_from: Option<PathBuf>,
+}
+
+pub struct GitRepository {
+    repository: Repository,
+    source: GitSource,
+    /// Branch checked out by the clone, used when `git_ref` isn't set.
+    default_branch: String,
+    // The clone is removed when this is dropped
+    _dir: TempDir,
+}
+
+impl GitRepository {
+    pub fn new(source: &GitSource) -> Result<Self> {
+        info!("Cloning {}...", source.url);
+        let dir = TempDir::new("spacehub")?;
+        let repository = RepoBuilder::new()
+            .bare(false)
+            .fetch_options(fetch_options(&source.url, source.auth.as_ref())?)
+            .clone(&source.url, &dir.path().join("git"))?;
+        let default_branch = repository
+            .head()?
+            .shorthand()
+            .ok_or_else(|| eyre!("Could not find the default branch of {}", source.url))?
+            .to_string();
+
+        let repository = Self {
+            repository,
+            source: source.clone(),
+            default_branch,
+            _dir: dir,
+        };
+        if repository.source.git_ref.is_some() {
+            // Tags are not fetched by the clone
+            repository.fetch()?;
+        }
+        repository.checkout()?;
+
+        // Fail before syncing rather than when pushing the room IDs
+        if source.record_room_ids.is_some() && repository.branch().is_none() {
+            return Err(eyre!(
+                "Can't record room IDs as {} is not a branch",
+                source
+                    .git_ref
+                    .as_ref()
+                    .unwrap_or(&repository.default_branch)
+            ));
+        }
+
+        Ok(repository)
+    }
+
+    /// The checked out branch, or None if the checked out ref is not a branch.
+    pub fn branch(&self) -> Option<&str> {
+        let git_ref = self.source.git_ref.as_ref().unwrap_or(&self.default_branch);
+        self.repository
+            .find_reference(&format!("refs/remotes/origin/{}", git_ref))
+            .ok()
+            .map(|_| git_ref.as_str())
+    }
+
+    pub fn path(&self) -> &Path {
+        // We never clone bare repositories
+        self.repository.workdir().unwrap()
+    }
+
+    /// The commit currently checked out.
+    pub fn head(&self) -> Result<String> {
+        Ok(self.repository.head()?.peel_to_commit()?.id().to_string())
+    }
+
+    /// Get the files changed since the given commit, or None if that commit is unknown.
+    pub fn changed_files(&self, since: &str) -> Result<Option<Vec<ChangedFile>>> {
+        let since = match Oid::from_str(since).and_then(|oid| self.repository.find_commit(oid)) {
+            Ok(since) => since,
+            Err(_) => return Ok(None),
+        };
+        let head = self.repository.head()?.peel_to_commit()?;
+        let mut diff =
+            self.repository
+                .diff_tree_to_tree(Some(&since.tree()?), Some(&head.tree()?), None)?;
+        diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+
+        let mut changed_files: Vec<ChangedFile> = vec![];
+        for delta in diff.deltas() {
+            let added_or_removed = delta.status() != Delta::Modified;
+            let old_path = delta.old_file().path().map(Path::to_path_buf);
+            let new_path = delta.new_file().path().map(Path::to_path_buf);
+            let moved_from = match delta.status() {
+                Delta::Renamed => old_path.clone(),
+                _ => None,
+            };
+            for (path, moved_from) in vec![(old_path, None), (new_path, moved_from)] {
+                let path = match path {
+                    Some(path) => path,
+                    None => continue,
+                };
+                match changed_files.iter_mut().find(|f| f.path == path) {
+                    Some(file) => file.moved_from = file.moved_from.take().or(moved_from),
+                    None => changed_files.push(ChangedFile {
+                        path,
+                        added_or_removed,
+                        moved_from,
+                    }),
+                }
+            }
+        }
+
+        Ok(Some(changed_files))
+    }
+
+    fn fetch(&self) -> Result<()> {
+        let mut remote = self.repository.find_remote("origin")?;
+        remote.fetch(
+            &[
+                "+refs/heads/*:refs/remotes/origin/*",
+                "+refs/tags/*:refs/tags/*",
+            ],
+            Some(&mut fetch_options(
+                &self.source.url,
+                self.source.auth.as_ref(),
+            )?),
+            None,
+        )?;
+
+        Ok(())
+    }
+
+    /// Check out the configured branch, tag or commit, or the default branch otherwise.
+    fn checkout(&self) -> Result<()> {
+        let git_ref = self.source.git_ref.as_ref().unwrap_or(&self.default_branch);
+
+        let mut commit = None;
+        for name in &[
+            format!("refs/remotes/origin/{}", git_ref),
+            format!("refs/tags/{}", git_ref),
+        ] {
+            if let Ok(reference) = self.repository.find_reference(name) {
+                commit = Some(reference.peel_to_commit()?);
+                break;
+            }
+        }
+        let commit = match commit {
+            Some(commit) => commit,
+            // Not a branch nor a tag, so hopefully a commit
+            None => self
+                .repository
+                .revparse_single(git_ref)
+                .map_err(|e| eyre!("Could not find {}: {}", git_ref, e))?
+                .peel_to_commit()?,
+        };
+
+        if let Some(signatures_config) = &self.source.verify_signatures {
+            self.verify_signature(commit.id(), signatures_config)?;
+        }
+
+        self.repository.set_head_detached(commit.id())?;
+        self.repository
+            .reset(commit.as_object(), ResetType::Hard, None)?;
+        info!("Checked out {} at {}", git_ref, commit.id());
+
+        Ok(())
+    }
+
+    /// Check that the commit is signed by one of the allowed GPG or SSH keys.
+    fn verify_signature(&self, commit: Oid, config: &SignaturesConfig) -> Result<()> {
+        let (signature, signed_data) = self
+            .repository
+            .extract_signature(&commit, None)
+            .map_err(|_| eyre!("Refusing to use commit {} as it is not signed", commit))?;
+        let signature = signature
+            .as_str()
+            .ok_or_else(|| eyre!("Signature of commit {} is not valid UTF-8", commit))?;
+
+        // gpg and ssh-keygen need both the signature and the signed data in files
+        let dir = TempDir::new("spacehub-signature")?;
+        let signature_path = dir.path().join("signature");
+        let data_path = dir.path().join("data");
+        std::fs::write(&signature_path, signature)?;
+        std::fs::write(&data_path, &*signed_data)?;
+
+        if signature.starts_with("-----BEGIN SSH SIGNATURE-----") {
+            let allowed_signers = config.ssh_allowed_signers.as_ref().ok_or_else(|| {
+                eyre!(
+                    "Refusing to use commit {} as it has an SSH signature and no SSH keys are allowed",
+                    commit
+                )
+            })?;
+
+            let output = Command::new("ssh-keygen")
+                .args(&["-Y", "find-principals", "-f"])
+                .arg(allowed_signers)
+                .arg("-s")
+                .arg(&signature_path)
+                .output()?;
+            let principal = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            if !output.status.success() || principal.is_empty() {
+                return Err(eyre!(
+                    "Refusing to use commit {} as it is not signed by an allowed SSH key",
+                    commit
+                ));
+            }
+
+            let output = Command::new("ssh-keygen")
+                .args(&["-Y", "verify", "-n", "git", "-f"])
+                .arg(allowed_signers)
+                .arg("-I")
+                .arg(&principal)
+                .arg("-s")
+                .arg(&signature_path)
+                .stdin(File::open(&data_path)?)
+                .output()?;
+            if !output.status.success() {
+                return Err(eyre!(
+                    "Refusing to use commit {} as its SSH signature by {} is invalid: {}",
+                    commit,
+                    principal,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            info!("Commit {} is signed by {}", commit, principal);
+        } else {
+            let fingerprints = config.gpg_fingerprints.as_ref().ok_or_else(|| {
+                eyre!(
+                    "Refusing to use commit {} as it has a GPG signature and no GPG keys are allowed",
+                    commit
+                )
+            })?;
+
+            let mut command = Command::new("gpg");
+            if let Some(gpg_home) = &config.gpg_home {
+                command.arg("--homedir").arg(gpg_home);
+            }
+            let output = command
+                .args(&["--status-fd", "1", "--verify"])
+                .arg(&signature_path)
+                .arg(&data_path)
+                .output()?;
+
+            // VALIDSIG only means that the signature is correct, GOODSIG is also needed for it to
+            // be made by a key that is neither expired nor revoked
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let statuses: Vec<&str> = stdout
+                .lines()
+                .filter_map(|l| l.strip_prefix("[GNUPG:] "))
+                .filter_map(|l| l.split(' ').next())
+                .collect();
+            let rejected = ["BADSIG", "EXPSIG", "EXPKEYSIG", "REVKEYSIG", "ERRSIG"];
+            if !statuses.contains(&"GOODSIG") || statuses.iter().any(|s| rejected.contains(s)) {
+                return Err(eyre!(
+                    "Refusing to use commit {} as its GPG signature is not good: {}",
+                    commit,
+                    statuses.join(", ")
+                ));
+            }
+
+            // VALIDSIG is followed by the fingerprint of the signing key, and ends with the one of
+            // its primary key
+            let signer = stdout
+                .lines()
+                .filter_map(|l| l.strip_prefix("[GNUPG:] VALIDSIG "))
+                .flat_map(|l| {
+                    let fields: Vec<&str> = l.split(' ').collect();
+                    vec![fields.first().copied(), fields.last().copied()]
+                })
+                .flatten()
+                .find(|fingerprint| {
+                    fingerprints
+                        .iter()
+                        .any(|f| f.replace(' ', "").eq_ignore_ascii_case(fingerprint))
+                });
+            match signer {
+                Some(signer) => info!("Commit {} is signed by {}", commit, signer),
+                None => {
+                    return Err(eyre!(
+                    "Refusing to use commit {} as it is not validly signed by an allowed GPG key",
+                    commit
+                ))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fetch the remote and check out the latest commit of the configured reference.
+    pub fn update(&self) -> Result<()> {
+        info!("Fetching updates...");
+        self.fetch()?;
+        self.checkout()
+    }
+
+    /// Commit changes to the given files, relative to the root of the repository, and push them
+    /// to the checked out branch.
+    pub fn commit_and_push(
+        &self,
+        paths: &[PathBuf],
+        message: &str,
+        committer: &CommitterConfig,
+    ) -> Result<()> {
+        let git_ref = self.branch().ok_or_else(|| {
+            eyre!(
+                "Cannot push to {} as it is not a branch",
+                self.source.git_ref.as_ref().unwrap_or(&self.default_branch)
+            )
+        })?;
+
+        let mut index = self.repository.index()?;
+        for path in paths {
+            index.add_path(path)?;
+        }
+        index.write()?;
+        let tree = self.repository.find_tree(index.write_tree()?)?;
+        let signature = Signature::now(&committer.name, &committer.email)?;
+        let parent = self.repository.head()?.peel_to_commit()?;
+        let commit = self.repository.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &[&parent],
+        )?;
+
+        // Rejected updates are not reported as errors by push itself
+        let mut rejection = None;
+        let mut callbacks = remote_callbacks(&self.source.url, self.source.auth.as_ref())?;
+        callbacks.push_update_reference(|_, status| {
+            rejection = status.map(String::from);
+            Ok(())
+        });
+        let mut push_options = PushOptions::new();
+        push_options.remote_callbacks(callbacks);
+        self.repository.find_remote("origin")?.push(
+            &[format!("HEAD:refs/heads/{}", git_ref)],
+            Some(&mut push_options),
+        )?;
+        drop(push_options);
+        if let Some(rejection) = rejection {
+            return Err(eyre!(
+                "Push of {} to {} was rejected: {}",
+                commit,
+                git_ref,
+                rejection
+            ));
+        }
+
+        info!("Pushed {} to {}", commit, git_ref);
+        Ok(())
+    }
+}
+
+fn fetch_options<'a>(url: &str, auth: Option<&'a GitAuthConfig>) -> Result<FetchOptions<'a>> {
+    let mut fetch_options = FetchOptions::new();
+    fetch_options.remote_callbacks(remote_callbacks(url, auth)?);
+    Ok(fetch_options)
+}
+
+fn remote_callbacks<'a>(url: &str, auth: Option<&'a GitAuthConfig>) -> Result<RemoteCallbacks<'a>> {
+    let auth = match auth {
+        Some(auth) => auth,
+        None => return Ok(RemoteCallbacks::new()),
+    };
+
+    let token = match (&auth.token_file, &auth.token_env) {
+        (Some(token_file), _) => Some(std::fs::read_to_string(token_file)?.trim().to_string()),
+        (None, Some(token_env)) => Some(std::env::var(token_env)?),
+        (None, None) => None,
+    };
+
+    let mut callbacks = RemoteCallbacks::new();
+    // libgit2 keeps asking for credentials as long as they are refused
+    let mut attempts = 0;
+    callbacks.credentials(move |url, username_from_url, allowed_types| {
+        attempts += 1;
+        if attempts > 3 {
+            return Err(git2::Error::from_str("Authentication failed"));
+        }
+
+        let username = auth
+            .username
+            .as_deref()
+            .or(username_from_url)
+            .unwrap_or("git");
+        if allowed_types.contains(CredentialType::SSH_KEY) {
+            if auth.ssh_agent.unwrap_or(false) {
+                return Cred::ssh_key_from_agent(username);
+            }
+            if let Some(ssh_key) = &auth.ssh_key {
+                return Cred::ssh_key(username, None, ssh_key, auth.ssh_key_passphrase.as_deref());
+            }
+        }
+        if allowed_types.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some(token) = &token {
+                return Cred::userpass_plaintext(username, token);
+            }
+            if auth.credential_helper.unwrap_or(false) {
+                return Cred::credential_helper(
+                    &git2::Config::open_default()?,
+                    url,
+                    Some(username),
+                );
+            }
+        }
+
+        Err(git2::Error::from_str("No suitable credentials configured"))
+    });
+
+    if let Some(known_hosts) = &auth.known_hosts {
+        // Accepting the certificate of an HTTPS remote here would bypass its verification
+        if url.starts_with("http://") || url.starts_with("https://") {
+            warn!("known_hosts is ignored for HTTP(S) repositories");
+        } else {
+            let known_hosts = load_known_hosts(known_hosts)?;
+            callbacks.certificate_check(move |cert, host| {
+                let hash = match cert.as_hostkey().and_then(|h| h.hash_sha256()) {
+                    Some(hash) => hash,
+                    None => return false,
+                };
+                let known = known_hosts
+                    .iter()
+                    .any(|(hosts, key_hash)| hosts.iter().any(|h| h == host) && key_hash == hash);
+                if !known {
+                    error!("Host key of {} is not in known_hosts", host);
+                }
+                known
+            });
+        }
+    }
+
+    Ok(callbacks)
+}
+
+/// Parse a known_hosts file into the hosts of each line, and the SHA256 of their key.
+fn load_known_hosts(path: &Path) -> Result<Vec<(Vec<String>, [u8; 32])>> {
+    let mut known_hosts = vec![];
+
+    for line in std::fs::read_to_string(path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[0].starts_with('@') {
+            warn!("Ignoring unsupported known_hosts line {}", line);
+            continue;
+        }
+        if fields[0].starts_with('|') {
+            warn!("Hashed known_hosts entries are not supported");
+            continue;
+        }
+
+        let hosts = fields[0]
+            .split(',')
+            .map(|h| {
+                // [host]:port
+                h.trim_start_matches('[')
+                    .split(']')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string()
+            })
+            .collect();
+        let key_hash = Sha256::digest(&base64::decode(fields[2])?).into();
+        known_hosts.push((hosts, key_hash));
+    }
+
+    Ok(known_hosts)
+}
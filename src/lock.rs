@@ -0,0 +1,82 @@
+use crate::folders::node_path;
+use crate::git::ChangedFile;
+use crate::report::SyncReport;
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use tracing::*;
+
+/// The rooms the spaces and rooms of the tree have been reconciled to, by path in the tree.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Lock {
+    pub rooms: BTreeMap<PathBuf, String>,
+}
+
+impl Lock {
+    /// Load the lockfile, or an empty one if it doesn't exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_yaml::from_str(&contents)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_yaml::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn room_id(&self, path: &Path) -> Option<&String> {
+        self.rooms.get(path)
+    }
+
+    /// Follow the files and folders moved since the lockfile was written, so that they keep their
+    /// room.
+    pub fn follow_moves(&mut self, changed_files: &[ChangedFile]) {
+        for file in changed_files {
+            let from = match &file.moved_from {
+                Some(from) => from,
+                None => continue,
+            };
+            let (from, to) = match (node_path(from), node_path(&file.path)) {
+                (Some(from), Some(to)) => (from, to),
+                _ => continue,
+            };
+
+            // Moving a folder moves everything in it
+            let moved: Vec<PathBuf> = self
+                .rooms
+                .keys()
+                .filter(|path| path.starts_with(from))
+                .cloned()
+                .collect();
+            for path in moved {
+                let new_path = to.join(path.strip_prefix(from).unwrap());
+                let room_id = self.rooms.remove(&path).unwrap();
+                info!(
+                    "{} has been moved to {}, keeping room {}",
+                    path.display(),
+                    new_path.display(),
+                    room_id
+                );
+                self.rooms.insert(new_path, room_id);
+            }
+        }
+    }
+
+    /// Record the rooms reconciled during the sync, and forget the paths that are not in the tree
+    /// anymore.
+    pub fn update<'a>(&mut self, report: &SyncReport, paths: impl Iterator<Item = &'a PathBuf>) {
+        for room in report.rooms() {
+            if let Some(room_id) = &room.room_id {
+                self.rooms.insert(room.path.clone(), room_id.clone());
+            }
+        }
+
+        let paths: HashSet<&PathBuf> = paths.collect();
+        self.rooms.retain(|path, _| paths.contains(path));
+    }
+}
@@ -1,21 +1,45 @@
-use crate::config::Config;
+use crate::actions::Action;
+use crate::config::{Config, Source, WebhookConfig};
+use crate::git::GitRepository;
+use crate::lock::Lock;
+use crate::matrix::MatrixClient;
+use crate::report::{SpaceReport, SyncReport};
+use crate::state::{Memberships, State};
 use clap::Parser;
-use color_eyre::eyre::Result;
-use std::collections::HashSet;
-use std::path::PathBuf;
-use tempdir::TempDir;
+use color_eyre::eyre::{eyre, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
 use tracing::*;
 
+mod actions;
 mod config;
 mod folders;
+mod git;
 mod ldap;
+mod lock;
 mod matrix;
+mod report;
+mod state;
+mod webhook;
 
 #[derive(Parser, Debug)]
 #[clap(about, version, author)]
 struct Opts {
     #[clap(short, long, parse(from_os_str))]
     config: PathBuf,
+    /// Print what would be changed on the homeserver without changing anything.
+    #[clap(long)]
+    dry_run: bool,
+    /// Write a JSON report of the sync to this file, or to stdout if `-`.
+    #[clap(long, parse(from_os_str))]
+    report: Option<PathBuf>,
+    /// Keep running and sync periodically.
+    #[clap(long)]
+    daemon: bool,
 }
 
 #[tokio::main]
@@ -24,27 +48,202 @@ async fn main() -> Result<()> {
     if std::env::var("RUST_SPANTRACE").is_err() {
         std::env::set_var("RUST_SPANTRACE", "0");
     }
-    tracing_subscriber::fmt()
-        .with_thread_names(true)
-        .with_max_level(tracing::Level::INFO)
-        .json()
-        .init();
 
     let opts = Opts::parse();
 
+    let subscriber = tracing_subscriber::fmt()
+        .with_thread_names(true)
+        .with_max_level(tracing::Level::INFO)
+        .json();
+    // In dry-run mode, the planned actions are printed on stdout, and the report may be written to
+    // it, so keep it clean
+    let report_to_stdout = opts.report.as_deref() == Some(Path::new("-"));
+    if opts.dry_run || report_to_stdout {
+        subscriber.with_writer(std::io::stderr).init();
+    } else {
+        subscriber.init();
+    }
+
     info!("Loading config...");
-    let config = Config::load(opts.config)?;
+    let config = Config::load(&opts.config)?;
 
-    let matrix_client = &mut matrix::MatrixClient::new(&config.matrix).await?;
-    let mut ldap_client = ldap::LdapClient::new(&config.ldap).await?;
+    // Keep stdout valid JSON when the report is written to it
+    let matrix_client =
+        matrix::MatrixClient::new(&config.matrix, opts.dry_run, report_to_stdout).await?;
+
+    if opts.daemon {
+        return daemon(&config, &opts, &matrix_client).await;
+    }
+
+    let mut report = SyncReport {
+        dry_run: opts.dry_run,
+        ..Default::default()
+    };
+    let mut repository = None;
+    let result = match update_source(&config.source, &mut repository) {
+        Ok((path, repository)) => {
+            sync(&config, &matrix_client, path, repository, &mut report).await
+        }
+        Err(e) => Err(e),
+    };
+
+    finish(&opts, &mut report, result)
+}
+
+/// Get the path to the up to date folder tree and the repository it comes from, cloning the
+/// repository the first time, and only fetching the changes afterwards.
+fn update_source<'a>(
+    source: &'a Source,
+    repository: &'a mut Option<GitRepository>,
+) -> Result<(&'a Path, Option<&'a GitRepository>)> {
+    match source {
+        Source::Local(path) => Ok((path.as_path(), None)),
+        Source::Git(git_source) => {
+            match repository.as_mut() {
+                Some(repository) => repository.update()?,
+                None => *repository = Some(GitRepository::new(git_source)?),
+            }
+            let repository = repository.as_ref().unwrap();
+            Ok((repository.path(), Some(repository)))
+        }
+    }
+}
+
+/// Write the report of a sync if needed, and fail if anything went wrong during it.
+fn finish(opts: &Opts, report: &mut SyncReport, result: Result<()>) -> Result<()> {
+    if let Some(report_path) = &opts.report {
+        if let Err(e) = &result {
+            report.error = Some(format!("{:#}", e));
+        }
+        report.write(report_path)?;
+    }
+    result?;
+
+    let errors = report.errors();
+    if !errors.is_empty() {
+        for e in &errors {
+            error!("{}", e);
+        }
+        return Err(eyre!(
+            "Sync finished with {} error(s):\n{}",
+            errors.len(),
+            errors.join("\n")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Sync periodically, reusing the same clone of the repository if any, until we are asked to stop.
+async fn daemon(config: &Config, opts: &Opts, matrix_client: &MatrixClient) -> Result<()> {
+    let interval = Duration::from_secs(
+        config
+            .daemon
+            .as_ref()
+            .and_then(|d| d.interval)
+            .unwrap_or(300),
+    );
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut repository: Option<GitRepository> = None;
+
+    let trigger = Arc::new(Notify::new());
+    // The webhook server is started once the branch is known, after the first clone
+    let mut webhook_config = config.daemon.as_ref().and_then(|d| d.webhook.clone());
+
+    loop {
+        let mut report = SyncReport {
+            dry_run: opts.dry_run,
+            ..Default::default()
+        };
+
+        let result = match update_source(&config.source, &mut repository) {
+            Ok((path, repository)) => {
+                if let (Some(repository), Some(webhook_config)) =
+                    (repository, webhook_config.take())
+                {
+                    spawn_webhook(webhook_config, repository, trigger.clone());
+                }
+                sync(config, matrix_client, path, repository, &mut report).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = finish(opts, &mut report, result) {
+            error!("{:#}", e);
+        }
+
+        info!("Next sync in {} seconds", interval.as_secs());
+        // Signals received during a sync are only handled once it is over
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => (),
+            _ = trigger.notified() => info!("Sync triggered by a webhook"),
+            _ = sigterm.recv() => break,
+            _ = sigint.recv() => break,
+        }
+    }
+
+    info!("Shutting down");
+    Ok(())
+}
+
+/// Listen for pushes to the branch of the repository.
+fn spawn_webhook(webhook_config: WebhookConfig, repository: &GitRepository, trigger: Arc<Notify>) {
+    let branch = match repository.branch() {
+        Some(branch) => branch.to_string(),
+        None => {
+            error!("Not listening for webhooks as the checked out ref is not a branch");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        if let Err(e) = webhook::serve(webhook_config, branch, trigger).await {
+            error!("Webhook server stopped: {:#}", e);
+        }
+    });
+}
+
+async fn sync(
+    config: &Config,
+    matrix_client: &MatrixClient,
+    path: &Path,
+    repository: Option<&GitRepository>,
+    report: &mut SyncReport,
+) -> Result<()> {
+    let commit = repository.map(GitRepository::head).transpose()?;
+    let previous_state = match &config.state_file {
+        Some(state_file) => State::load(state_file)?,
+        None => State::default(),
+    };
+
+    // Without a commit, we can't know what changed in a local folder tree
+    let changed_files = match (&previous_state.commit, repository) {
+        (Some(previous_commit), Some(repository)) => {
+            let changed_files = repository.changed_files(previous_commit)?;
+            if changed_files.is_none() {
+                warn!(
+                    "Commit {} of the last sync is unknown, syncing everything",
+                    previous_commit
+                );
+            }
+            changed_files
+        }
+        _ => None,
+    };
 
-    info!("Cloning {}...", config.git_repository);
-    let git_path = TempDir::new("spacehub")?.path().join("git");
-    let _ = git2::build::RepoBuilder::new()
-        .bare(false)
-        .clone(&config.git_repository, &git_path)?;
+    let lockfile = config.lockfile.as_ref().map(|lockfile| path.join(lockfile));
+    let mut lock = match &lockfile {
+        Some(lockfile) => Lock::load(lockfile)?,
+        None => Lock::default(),
+    };
+    let previous_lock = lock.clone();
+    if let Some(changed_files) = &changed_files {
+        lock.follow_moves(changed_files);
+    }
 
-    let mut space_folders = folders::SpaceFolder::new(git_path.as_path())?;
+    // The LDAP connection would not survive between syncs in daemon mode
+    let mut ldap_client = ldap::LdapClient::new(&config.ldap).await?;
+
+    let mut space_folders = folders::SpaceFolder::new(path, &lock, config.default_names.as_ref())?;
 
     for folder in &space_folders {
         folder.check()?;
@@ -58,7 +257,44 @@ async fn main() -> Result<()> {
                 &config.matrix.server_name,
                 config.ldap.synapse_external_ids.as_ref(),
             )
-            .await?;
+            .await;
+    }
+
+    let mut memberships = Memberships::new();
+    for folder in &space_folders {
+        folder.memberships(&mut memberships);
+    }
+    let mut state = State::new(commit.as_deref(), config, &memberships)?;
+
+    // Everything has to be reconciled again when the configuration changes
+    let changed_files = changed_files.filter(|_| {
+        let config_changed = state.config != previous_state.config;
+        if config_changed {
+            info!("The configuration changed since the last sync, syncing everything");
+        }
+        !config_changed
+    });
+
+    // Only reconcile what changed since the last sync
+    let selected = changed_files.map(|changed_files| {
+        let mut selected = HashSet::new();
+        folders::select_changed_files(&changed_files, &mut selected);
+        for folder in &space_folders {
+            folder.select_changed_avatars(&changed_files, &mut selected);
+        }
+        for (path, hash) in &state.memberships {
+            if hash.is_none() || previous_state.memberships.get(path) != Some(hash) {
+                selected.insert(path.clone());
+            }
+        }
+        selected
+    });
+    if let Some(selected) = &selected {
+        if selected.is_empty() {
+            info!("Nothing changed since the last sync, skipping it.");
+            return Ok(());
+        }
+        info!("Syncing {} changed spaces and rooms.", selected.len());
     }
 
     if config.ldap.create_missing_users {
@@ -66,19 +302,100 @@ async fn main() -> Result<()> {
         let users = space_folders
             .iter()
             .fold(HashSet::new(), |mut acc, folder| {
-                acc.extend(folder.get_all_users());
+                acc.extend(folder.get_all_users(selected.as_ref()));
                 acc
             });
 
         for user in users {
-            matrix_client.ensure_user(&user).await?;
+            let result = matrix_client.ensure_user(&user).await;
+            report
+                .users_created
+                .extend(matrix_client.take_actions().into_iter().filter_map(
+                    |action| match action {
+                        Action::RegisterUser { user_id } => Some(user_id),
+                        _ => None,
+                    },
+                ));
+            if let Err(e) = result {
+                error!("Failed to create user {}: {:#}", user.mxid, e);
+                report.users_errors.insert(user.mxid, format!("{:#}", e));
+            }
         }
     }
 
     info!("Processing spaces and rooms.");
     for folder in space_folders {
-        folder.folders_to_matrix(matrix_client, None).await?;
+        if !selected.as_ref().map_or(true, |s| folder.needs_sync(s)) {
+            continue;
+        }
+        let mut space_report = SpaceReport::default();
+        // Errors are recorded in the report
+        let _ = folder
+            .folders_to_matrix(
+                matrix_client,
+                &folder.parents(),
+                &mut BTreeMap::new(),
+                selected.as_ref(),
+                &mut space_report,
+            )
+            .await;
+        report.spaces.push(space_report);
+    }
+
+    if report.dry_run {
+        return Ok(());
+    }
+
+    // Files of the folder tree changed by the sync
+    let mut changed_paths = vec![];
+
+    if let Some(lockfile) = &lockfile {
+        // Nodes of the tree are exactly the ones we know the users of
+        lock.update(report, memberships.keys());
+        if lock != previous_lock {
+            lock.save(lockfile)?;
+            if let Some(lockfile) = config.lockfile.as_ref().filter(|l| l.is_relative()) {
+                changed_paths.push(lockfile.clone());
+            }
+        }
+    }
+
+    if let (Source::Git(git_source), Some(repository)) = (&config.source, repository) {
+        if let Some(committer) = &git_source.record_room_ids {
+            changed_paths.extend(record_room_ids(repository.path(), report)?);
+            if !changed_paths.is_empty() {
+                repository.commit_and_push(&changed_paths, "Record room IDs", committer)?;
+                // Our own commit has been applied too
+                state.commit = Some(repository.head()?);
+            }
+        }
+    }
+
+    if let Some(state_file) = &config.state_file {
+        // Whatever failed has to be retried by the next sync
+        if report.errors().is_empty() {
+            state.save(state_file)?;
+        }
     }
 
     Ok(())
 }
+
+/// Record the IDs of the rooms defined by their alias in the files defining them, so that they
+/// are still found if their alias is changed or reassigned. Rooms created by a sync whose push
+/// failed are recorded by the next one. Returns the changed files.
+fn record_room_ids(root: &Path, report: &SyncReport) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for room in report.rooms() {
+        let room_id = match (&room.alias, &room.room_id) {
+            (Some(_), Some(room_id)) => room_id,
+            _ => continue,
+        };
+        if let Some(path) = folders::record_room_id(root, &room.path, room_id)? {
+            info!("Recorded room ID {} in {}", room_id, path.display());
+            paths.push(path);
+        }
+    }
+
+    Ok(paths)
+}
@@ -1,42 +1,133 @@
+use crate::actions::Action;
 use crate::config::MatrixConfig;
-use crate::folders::UserMetadata;
-use color_eyre::eyre::{ErrReport, Result};
+use crate::folders::{
+    avatar_content_type, DirectoryVisibility, GuestAccess, HistoryVisibility, JoinRule,
+    UserMetadata,
+};
+use color_eyre::eyre::{eyre, ErrReport, Result};
 use matrix_sdk::{
     ruma::{
         api::{
             client::{
                 error as ruma_api_client_error,
-                r0::{alias, profile, room},
+                r0::{
+                    alias, capabilities,
+                    directory::{get_room_visibility, set_room_visibility},
+                    membership::{self, invite_user},
+                    profile,
+                    room::{create_room, Visibility},
+                    state,
+                },
             },
             error as ruma_api_error,
         },
-        RoomAliasId, RoomId, UserId,
+        events::room::create::RoomType,
+        serde::Raw,
+        RoomAliasId, RoomId, RoomVersionId, UserId,
     },
     Client, ClientConfig, HttpError, RequestConfig,
 };
+use regex::Regex;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
 use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::Mutex;
 use synapse_admin_api::users as synapse_users;
 use tracing::*;
 
+/// Key of the `m.room.avatar` content recording the SHA256 of the image, so that it is only
+/// uploaded again when it changes.
+const AVATAR_HASH_KEY: &str = "spacehub.sha256";
+
 pub struct MatrixClient {
     client: Client,
+    http_client: reqwest::Client,
+    homeserver_url: String,
+    access_token: String,
+    server_name: String,
+    mxid: String,
+    protected_users: Vec<Regex>,
+    force_join: bool,
+    kick_reason: Option<String>,
+    appservice_token: Option<String>,
+    dry_run: bool,
+    /// Print the planned actions to stderr rather than stdout, which is used by the report.
+    plan_to_stderr: bool,
+    /// Aliases of the rooms that would have been created, and IDs of the rooms that would have been
+    /// joined, in dry-run mode.
+    planned_rooms: Mutex<HashSet<String>>,
+    /// Actions performed, or planned, since the last call to `take_actions`.
+    actions: Mutex<Vec<Action>>,
 }
 
 impl MatrixClient {
     #[instrument(skip(config))]
-    pub async fn new(config: &MatrixConfig) -> Result<Self> {
+    pub async fn new(config: &MatrixConfig, dry_run: bool, plan_to_stderr: bool) -> Result<Self> {
         info!("Beginning Matrix setup");
 
         let client_config = ClientConfig::new().user_agent("spacehub")?;
         let client = Client::new_with_config(config.homeserver_url.parse()?, client_config)?;
 
         info!("Logging in to Matrix");
-        let _response = client
+        let response = client
             .login(&config.mxid, &config.password, None, Some("spacehub"))
             .await?;
 
         info!("Finished setting up Matrix");
-        Ok(Self { client })
+        Ok(Self {
+            client,
+            http_client: reqwest::Client::builder().user_agent("spacehub").build()?,
+            homeserver_url: config.homeserver_url.clone(),
+            access_token: response.access_token,
+            server_name: config.server_name.clone(),
+            mxid: if config.mxid.starts_with('@') {
+                config.mxid.clone()
+            } else {
+                format!("@{}:{}", config.mxid, config.server_name)
+            },
+            protected_users: config
+                .protected_users
+                .iter()
+                .flatten()
+                .map(|r| Regex::new(&format!("^(?:{})$", r)))
+                .collect::<Result<_, _>>()?,
+            force_join: config.force_join.unwrap_or(false),
+            kick_reason: config.kick_reason.clone(),
+            appservice_token: config.appservice_token.clone(),
+            dry_run,
+            plan_to_stderr,
+            planned_rooms: Mutex::new(HashSet::new()),
+            actions: Mutex::new(vec![]),
+        })
+    }
+
+    /// Announce an action, and tell whether it should actually be performed.
+    fn plan(&self, action: Action) -> bool {
+        let perform = if self.dry_run {
+            if self.plan_to_stderr {
+                eprintln!("{}", action);
+            } else {
+                println!("{}", action);
+            }
+            false
+        } else {
+            info!("{}", action);
+            true
+        };
+        self.actions.lock().unwrap().push(action);
+        perform
+    }
+
+    /// Get the actions performed, or planned, since the last call.
+    pub fn take_actions(&self) -> Vec<Action> {
+        std::mem::take(&mut *self.actions.lock().unwrap())
+    }
+
+    /// Whether the room would have been created in dry-run mode, in which case it doesn't exist.
+    fn is_planned(&self, room_id: &str) -> bool {
+        self.planned_rooms.lock().unwrap().contains(room_id)
     }
 
     async fn user_exists(&self, user_id: &UserId) -> Result<bool> {
@@ -63,7 +154,12 @@ impl MatrixClient {
             return Ok(());
         }
 
-        info!("Creating user {}", user.mxid);
+        if !self.plan(Action::RegisterUser {
+            user_id: user.mxid.clone(),
+        }) {
+            return Ok(());
+        }
+
         let mut register_request =
             synapse_users::create_or_modify::v2::Request::new(&user_id, None);
         register_request.external_ids = Some(vec![]);
@@ -80,128 +176,1081 @@ impl MatrixClient {
         Ok(())
     }
 
-    async fn create_room(&self) -> Result<RoomId> {
-        todo!();
+    async fn create_room(
+        &self,
+        room_alias_id: &RoomAliasId,
+        join_rule: JoinRule,
+        is_space: bool,
+    ) -> Result<Option<RoomId>> {
+        // The alias is registered by the homeserver when creating the room, which only works for
+        // aliases on our own server
+        if room_alias_id.server_name().as_str() != self.server_name {
+            return Err(eyre!(
+                "Cannot create room for alias {} as it is not on {}",
+                room_alias_id,
+                self.server_name
+            ));
+        }
+
+        let mut create_request = create_room::Request::new();
+        create_request.room_alias_name = Some(room_alias_id.alias());
+
+        // The join rule itself is set afterwards, as there are no presets for most of them, and so
+        // is the visibility in the room directory
+        if join_rule == JoinRule::Public {
+            create_request.preset = Some(create_room::RoomPreset::PublicChat);
+        } else {
+            create_request.preset = Some(create_room::RoomPreset::PrivateChat);
+        }
+
+        if !self.plan(Action::CreateRoom {
+            alias: room_alias_id.to_string(),
+            is_space,
+            join_rule: join_rule.to_string(),
+        }) {
+            return Ok(None);
+        }
+
+        // Use a room version supporting the join rule if the default one doesn't
+        let mut room_version = None;
+        if let Some(min_version) = join_rule.min_room_version() {
+            let response = self
+                .client
+                .send(capabilities::get_capabilities::Request::new(), None)
+                .await?;
+            let default_version = response.capabilities.room_versions.default;
+            if default_version
+                .as_str()
+                .parse::<u32>()
+                .map_or(false, |v| v < min_version)
+            {
+                room_version = Some(RoomVersionId::try_from(min_version.to_string().as_str())?);
+            }
+        }
+        create_request.room_version = room_version.as_ref();
+
+        if is_space {
+            create_request.creation_content.room_type = Some(RoomType::Space);
+            // Spaces are not meant to be chatted in
+            create_request.power_level_content_override = Some(Raw::from_json(
+                serde_json::value::to_raw_value(&json!({ "events_default": 100 }))?,
+            ));
+        }
+
+        let response = self.client.send(create_request, None).await?;
+        info!("Created {} as {}", room_alias_id, response.room_id);
+
+        Ok(Some(response.room_id))
+    }
+
+    async fn get_state(
+        &self,
+        room_id: &RoomId,
+        event_type: &str,
+        state_key: &str,
+    ) -> Result<Option<serde_json::Value>> {
+        let state_request =
+            state::get_state_events_for_key::Request::new(room_id, event_type.into(), state_key);
+
+        let content = match self.client.send(state_request, None).await {
+            Err(HttpError::ClientApi(ruma_api_error::FromHttpResponseError::Http(
+                ruma_api_error::ServerError::Known(e),
+            ))) if e.kind == ruma_api_client_error::ErrorKind::NotFound => None,
+            Err(e) => return Err(ErrReport::try_from(e)?),
+            Ok(response) => Some(serde_json::from_str(response.content.json().get())?),
+        };
+
+        Ok(content)
+    }
+
+    async fn send_state(
+        &self,
+        room_id: &RoomId,
+        event_type: &str,
+        state_key: &str,
+        content: serde_json::Value,
+    ) -> Result<()> {
+        let state_request = state::send_state_event::Request::new_raw(
+            room_id,
+            event_type,
+            state_key,
+            Raw::from_json(serde_json::value::to_raw_value(&content)?),
+        );
+        let _response = self.client.send(state_request, None).await?;
+
+        Ok(())
+    }
+
+    /// Get the state key and content of all the state events of a given type, ignoring the ones
+    /// that have been redacted by sending an empty content.
+    async fn get_state_events(
+        &self,
+        room_id: &RoomId,
+        event_type: &str,
+    ) -> Result<Vec<(String, serde_json::Value)>> {
+        let state_request = state::get_state_events::Request::new(room_id);
+        let room_state = self.client.send(state_request, None).await?.room_state;
+
+        let mut events = vec![];
+        for raw_event in room_state {
+            let event: serde_json::Value = serde_json::from_str(raw_event.json().get())?;
+            if event.get("type").and_then(|t| t.as_str()) != Some(event_type) {
+                continue;
+            }
+            let state_key = event
+                .get("state_key")
+                .and_then(|k| k.as_str())
+                .unwrap_or_default()
+                .to_string();
+            let content = event.get("content").cloned().unwrap_or_else(|| json!({}));
+            if content.as_object().map_or(true, |c| c.is_empty()) {
+                continue;
+            }
+            events.push((state_key, content));
+        }
+
+        Ok(events)
+    }
+
+    async fn resolve_alias(&self, room_alias_id: &RoomAliasId) -> Result<Option<RoomId>> {
+        let alias_request = alias::get_alias::Request::new(room_alias_id);
+        let room = match self
+            .client
+            .send(alias_request, Some(RequestConfig::new().force_auth()))
+            .await
+        {
+            Err(HttpError::ClientApi(ruma_api_error::FromHttpResponseError::Http(
+                ruma_api_error::ServerError::Known(e),
+            ))) if e.kind == ruma_api_client_error::ErrorKind::NotFound => None,
+            Err(e) => return Err(ErrReport::try_from(e)?),
+            Ok(room) => Some(room.room_id),
+        };
+
+        Ok(room)
+    }
+
+    /// Get the ID of an existing room, without reconciling anything.
+    pub async fn find_room(
+        &self,
+        room_id_s: Option<&String>,
+        alias: Option<&String>,
+    ) -> Result<String> {
+        match (room_id_s, alias) {
+            (Some(room_id_s), _) => Ok(room_id_s.clone()),
+            (None, Some(alias)) => match self
+                .resolve_alias(&RoomAliasId::try_from(alias.clone())?)
+                .await?
+            {
+                Some(room_id) => Ok(room_id.to_string()),
+                None => Err(eyre!("No room found for alias {}", alias)),
+            },
+            (None, None) => Err(eyre!("Room has neither an ID nor an alias")),
+        }
+    }
+
+    /// Join the room if we are not in it yet. Returns whether we are in the room, which is not the
+    /// case in dry-run mode if we would have to join it.
+    async fn ensure_joined(&self, room_id: &RoomId) -> Result<bool> {
+        let joined_rooms = self
+            .client
+            .send(membership::joined_rooms::Request::new(), None)
+            .await?
+            .joined_rooms;
+        if joined_rooms.contains(room_id) {
+            return Ok(true);
+        }
+
+        if !self.plan(Action::JoinRoom {
+            room_id: room_id.to_string(),
+        }) {
+            return Ok(false);
+        }
+
+        let join_request = membership::join_room_by_id::Request::new(room_id);
+        if let Err(e) = self.client.send(join_request, None).await {
+            return Err(eyre!(
+                "Room {} exists but we are not in it and could not join it: {}",
+                room_id,
+                e
+            ));
+        }
+
+        Ok(true)
+    }
+
+    /// Treat a room whose state we cannot read in dry-run mode like an empty one, planning all of
+    /// what would be done to it.
+    fn plan_unknown_room(&self, room_id: &str, parent: Option<&str>) {
+        self.planned_rooms
+            .lock()
+            .unwrap()
+            .insert(room_id.to_string());
+        if let Some(parent) = parent {
+            self.plan(Action::SetParent {
+                room_id: room_id.to_string(),
+                parent: parent.to_string(),
+            });
+        }
     }
 
     pub async fn ensure_room(
         &self,
         room_id_s: Option<&String>,
         alias: Option<&String>,
-        visibility: &str,
+        join_rule: JoinRule,
+        allowed_rooms: &[String],
         is_space: bool,
         parent: Option<&str>,
     ) -> Result<String> {
-        let room_id: RoomId;
-        let room_alias_id: RoomAliasId;
+        let room_id = match (room_id_s, alias) {
+            (Some(room_id_s), _) => RoomId::try_from(room_id_s.clone())?,
+            (None, Some(alias)) => {
+                let room_alias_id = RoomAliasId::try_from(alias.clone())?;
 
-        if !room_id_s.is_some() {
-            room_alias_id = RoomAliasId::try_from(alias.unwrap().clone())?;
+                // If the alias is associated with a room, use it, otherwise, create it
+                match self.resolve_alias(&room_alias_id).await? {
+                    Some(room_id) => room_id,
+                    None => match self
+                        .create_room(&room_alias_id, join_rule, is_space)
+                        .await?
+                    {
+                        Some(room_id) => room_id,
+                        // In dry-run mode, the room doesn't exist, so there is nothing to check
+                        None => {
+                            // Only the public and invite join rules have a preset
+                            if join_rule != JoinRule::Public && join_rule != JoinRule::Invite {
+                                let allow = self.allow(join_rule, allowed_rooms, parent).await?;
+                                self.plan(Action::SetJoinRule {
+                                    room_id: alias.clone(),
+                                    join_rule: join_rule.to_string(),
+                                    allow,
+                                });
+                            }
+                            self.plan_unknown_room(alias, parent);
+                            return Ok(alias.clone());
+                        }
+                    },
+                }
+            }
+            (None, None) => return Err(eyre!("Room has neither an ID nor an alias")),
+        };
 
-            // Check if the alias is associated with a room
-            let alias_request = alias::get_alias::Request::new(&room_alias_id);
-            let room = match self
-                .client
-                .send(alias_request, Some(RequestConfig::new().force_auth()))
-                .await
+        // We now have a room, make sure we can manage it. In dry-run mode, we cannot read the
+        // state of a room we are not in yet
+        if !self.ensure_joined(&room_id).await? {
+            info!(
+                "Would join room {}, its current state is unknown until then",
+                room_id
+            );
+            self.plan_unknown_room(room_id.as_str(), parent);
+            return Ok(room_id.to_string());
+        }
+
+        if let (Some(_), Some(alias)) = (room_id_s, alias) {
+            self.ensure_alias(&room_id, alias).await?;
+        }
+
+        let create_content = self
+            .get_state(&room_id, "m.room.create", "")
+            .await?
+            .ok_or_else(|| eyre!("Room {} has no m.room.create event", room_id))?;
+        let room_is_space = create_content.get("type").and_then(|t| t.as_str()) == Some("m.space");
+        if room_is_space != is_space {
+            warn!(
+                "Room {} should {}be a space but was created as {}, this cannot be fixed",
+                room_id,
+                if is_space { "" } else { "not " },
+                if room_is_space { "a space" } else { "a room" }
+            );
+        }
+
+        let allow = self.allow(join_rule, allowed_rooms, parent).await?;
+        let room_version = create_content
+            .get("room_version")
+            .and_then(|v| v.as_str())
+            .unwrap_or("1");
+        self.ensure_join_rule(&room_id, room_version, join_rule, &allow)
+            .await?;
+
+        self.ensure_space_parent(&room_id, parent).await?;
+
+        Ok(room_id.to_string())
+    }
+
+    /// Rooms whose members can join a restricted room: its parent space and allowed rooms.
+    async fn allow(
+        &self,
+        join_rule: JoinRule,
+        allowed_rooms: &[String],
+        parent: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let mut allow = vec![];
+        if join_rule.is_restricted() {
+            allow.extend(parent.map(String::from));
+            for room in allowed_rooms {
+                // Rooms that would have been created in dry-run mode are known by their alias
+                if room.starts_with('#') && !self.is_planned(room) {
+                    allow.push(self.find_room(None, Some(room)).await?);
+                } else {
+                    allow.push(room.clone());
+                }
+            }
+        }
+
+        Ok(allow)
+    }
+
+    /// Make the alias of a room found by its ID point to it, and make it its canonical alias.
+    /// Aliases pointing to other rooms are left untouched.
+    async fn ensure_alias(&self, room_id: &RoomId, alias: &str) -> Result<()> {
+        let room_alias_id = RoomAliasId::try_from(alias)?;
+        match self.resolve_alias(&room_alias_id).await? {
+            Some(alias_room_id) if &alias_room_id == room_id => (),
+            Some(alias_room_id) => {
+                warn!(
+                    "Alias {} points to room {} rather than {}, leaving it",
+                    alias, alias_room_id, room_id
+                );
+                return Ok(());
+            }
+            None if room_alias_id.server_name().as_str() != self.server_name => {
+                warn!(
+                    "Cannot create alias {} for room {} on another server",
+                    alias, room_id
+                );
+                return Ok(());
+            }
+            None => {
+                if self.plan(Action::CreateAlias {
+                    room_id: room_id.to_string(),
+                    alias: alias.to_string(),
+                }) {
+                    let alias_request = alias::create_alias::Request::new(&room_alias_id, room_id);
+                    self.client.send(alias_request, None).await?;
+                }
+            }
+        }
+
+        let mut content = self
+            .get_state(room_id, "m.room.canonical_alias", "")
+            .await?
+            .unwrap_or_else(|| json!({}));
+        let current = content.get("alias").and_then(|a| a.as_str());
+        if current == Some(alias) {
+            return Ok(());
+        }
+        if let Some(current) = current {
+            warn!(
+                "Previous alias {} of room {} still points to it",
+                current, room_id
+            );
+        }
+        if self.plan(Action::SetCanonicalAlias {
+            room_id: room_id.to_string(),
+            alias: alias.to_string(),
+        }) {
+            content["alias"] = json!(alias);
+            self.send_state(room_id, "m.room.canonical_alias", "", content)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn ensure_join_rule(
+        &self,
+        room_id: &RoomId,
+        room_version: &str,
+        join_rule: JoinRule,
+        allow: &[String],
+    ) -> Result<()> {
+        let current = self.get_state(room_id, "m.room.join_rules", "").await?;
+        let current_join_rule = current
+            .as_ref()
+            .and_then(|c| c.get("join_rule"))
+            .and_then(|j| j.as_str());
+        let current_allow: HashSet<&str> = current
+            .as_ref()
+            .and_then(|c| c.get("allow"))
+            .and_then(|a| a.as_array())
+            .map(|a| {
+                a.iter()
+                    .filter_map(|e| e.get("room_id").and_then(|r| r.as_str()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        if current_join_rule == Some(join_rule.to_string().as_str())
+            && current_allow == allow.iter().map(String::as_str).collect()
+        {
+            return Ok(());
+        }
+
+        // Unstable room versions are not numbers, hope for the best with them
+        if let Some(min_version) = join_rule.min_room_version() {
+            if room_version
+                .parse::<u32>()
+                .map_or(false, |v| v < min_version)
             {
-                Err(HttpError::ClientApi(ruma_api_error::FromHttpResponseError::Http(
-                    ruma_api_error::ServerError::Known(e),
-                ))) if e.kind == ruma_api_client_error::ErrorKind::NotFound => None,
-                Err(e) => return Err(ErrReport::try_from(e)?),
-                Ok(room) => Some(room),
-            };
+                warn!(
+                    "Room {} must be upgraded from version {} to {} to use join rule {}, leaving it as is",
+                    room_id, room_version, min_version, join_rule
+                );
+                return Ok(());
+            }
+        }
+
+        if !self.plan(Action::SetJoinRule {
+            room_id: room_id.to_string(),
+            join_rule: join_rule.to_string(),
+            allow: allow.to_vec(),
+        }) {
+            return Ok(());
+        }
+
+        let mut content = json!({ "join_rule": join_rule.to_string() });
+        if join_rule.is_restricted() {
+            content["allow"] = allow
+                .iter()
+                .map(|room_id| json!({ "type": "m.room_membership", "room_id": room_id }))
+                .collect();
+        }
+        self.send_state(room_id, "m.room.join_rules", "", content)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Send a request to an endpoint that is not covered by ruma, such as some of the Synapse admin
+    /// API ones.
+    async fn send_raw(
+        &self,
+        method: reqwest::Method,
+        path: &[&str],
+        access_token: &str,
+        body: &serde_json::Value,
+    ) -> Result<()> {
+        let mut url = url::Url::parse(&self.homeserver_url)?;
+        url.path_segments_mut()
+            .map_err(|_| eyre!("Invalid homeserver URL {}", self.homeserver_url))?
+            .pop_if_empty()
+            .extend(path);
+
+        let response = self
+            .http_client
+            .request(method, url)
+            .bearer_auth(access_token)
+            .json(body)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(eyre!(
+                "Request to {} failed with {}: {}",
+                path.join("/"),
+                status,
+                response.text().await?
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Upload a file to the media repository, and get its MXC URI.
+    async fn upload(
+        &self,
+        contents: Vec<u8>,
+        content_type: &str,
+        file_name: &str,
+    ) -> Result<String> {
+        let mut url = url::Url::parse(&self.homeserver_url)?;
+        url.path_segments_mut()
+            .map_err(|_| eyre!("Invalid homeserver URL {}", self.homeserver_url))?
+            .pop_if_empty()
+            .extend(&["_matrix", "media", "r0", "upload"]);
+        url.query_pairs_mut().append_pair("filename", file_name);
 
-            // If it does, store its ID, otherwise, create it
-            room_id = match room {
-                Some(room) => room.room_id,
-                None => self.create_room().await?,
-            };
+        let response = self
+            .http_client
+            .post(url)
+            .bearer_auth(&self.access_token)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .body(contents)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(eyre!(
+                "Upload of {} failed with {}: {}",
+                file_name,
+                status,
+                response.text().await?
+            ));
         }
 
-        // We now have a room
+        let content: serde_json::Value = response.json().await?;
+        content
+            .get("content_uri")
+            .and_then(|c| c.as_str())
+            .map(String::from)
+            .ok_or_else(|| eyre!("Upload of {} returned no content URI", file_name))
+    }
 
-        todo!();
+    /// Get the content of a state event of a room, which planned rooms have none of.
+    async fn get_room_state(
+        &self,
+        room_id: &str,
+        event_type: &str,
+    ) -> Result<Option<serde_json::Value>> {
+        if self.is_planned(room_id) {
+            return Ok(None);
+        }
+        self.get_state(&RoomId::try_from(room_id)?, event_type, "")
+            .await
     }
 
-    pub async fn get_room_members(&self, room_id: &str) -> Result<Vec<String>> {
-        todo!();
+    /// Set a field of a state event of the room to the given value, unless it already is.
+    async fn ensure_state_field(
+        &self,
+        room_id: &str,
+        event_type: &str,
+        field: &str,
+        value: &str,
+        action: Action,
+    ) -> Result<()> {
+        let current = self.get_room_state(room_id, event_type).await?;
+        if current
+            .as_ref()
+            .and_then(|c| c.get(field))
+            .and_then(|v| v.as_str())
+            != Some(value)
+            && self.plan(action)
+        {
+            let mut content = serde_json::Map::new();
+            content.insert(field.to_string(), value.into());
+            self.send_state(
+                &RoomId::try_from(room_id)?,
+                event_type,
+                "",
+                serde_json::Value::Object(content),
+            )
+            .await?;
+        }
+
+        Ok(())
     }
 
-    pub async fn add_user_to_room(
+    /// Set the name, topic and avatar of the room, leaving the ones that are not given untouched.
+    pub async fn ensure_room_profile(
         &self,
         room_id: &str,
-        user_id: &str,
-        power_level: i32,
+        name: Option<&String>,
+        topic: Option<&String>,
+        avatar: Option<&Path>,
     ) -> Result<()> {
-        todo!();
+        if let Some(name) = name {
+            self.ensure_state_field(
+                room_id,
+                "m.room.name",
+                "name",
+                name,
+                Action::SetName {
+                    room_id: room_id.to_string(),
+                    name: name.clone(),
+                },
+            )
+            .await?;
+        }
+
+        if let Some(topic) = topic {
+            self.ensure_state_field(
+                room_id,
+                "m.room.topic",
+                "topic",
+                topic,
+                Action::SetTopic {
+                    room_id: room_id.to_string(),
+                    topic: topic.clone(),
+                },
+            )
+            .await?;
+        }
+
+        if let Some(avatar) = avatar {
+            let file_name = avatar
+                .file_name()
+                .and_then(|f| f.to_str())
+                .unwrap_or_default()
+                .to_string();
+            let content_type = avatar_content_type(avatar)
+                .ok_or_else(|| eyre!("Unsupported avatar format {}", avatar.display()))?;
+            let image = std::fs::read(avatar)
+                .map_err(|e| eyre!("Could not read avatar {}: {}", avatar.display(), e))?;
+            let hash = hex::encode(Sha256::digest(&image));
+
+            let current = self.get_room_state(room_id, "m.room.avatar").await?;
+            if current
+                .as_ref()
+                .and_then(|c| c.get(AVATAR_HASH_KEY))
+                .and_then(|h| h.as_str())
+                != Some(hash.as_str())
+                && self.plan(Action::SetAvatar {
+                    room_id: room_id.to_string(),
+                    avatar: file_name.clone(),
+                })
+            {
+                let size = image.len();
+                let url = self.upload(image, content_type, &file_name).await?;
+                self.send_state(
+                    &RoomId::try_from(room_id)?,
+                    "m.room.avatar",
+                    "",
+                    json!({
+                        "url": url,
+                        "info": { "mimetype": content_type, "size": size },
+                        AVATAR_HASH_KEY: hash,
+                    }),
+                )
+                .await?;
+            }
+        }
+
+        Ok(())
     }
 
-    pub async fn ensure_user_powerlevel(
+    /// Set the history visibility, guest access and encryption of the room, leaving the ones that
+    /// are not given untouched.
+    pub async fn ensure_room_settings(
         &self,
         room_id: &str,
-        user_id: &str,
-        power_level: i32,
+        history_visibility: Option<HistoryVisibility>,
+        guest_access: Option<GuestAccess>,
+        encryption: Option<bool>,
     ) -> Result<()> {
-        todo!();
+        if let Some(history_visibility) = history_visibility {
+            self.ensure_state_field(
+                room_id,
+                "m.room.history_visibility",
+                "history_visibility",
+                &history_visibility.to_string(),
+                Action::SetHistoryVisibility {
+                    room_id: room_id.to_string(),
+                    history_visibility: history_visibility.to_string(),
+                },
+            )
+            .await?;
+        }
+
+        if let Some(guest_access) = guest_access {
+            self.ensure_state_field(
+                room_id,
+                "m.room.guest_access",
+                "guest_access",
+                &guest_access.to_string(),
+                Action::SetGuestAccess {
+                    room_id: room_id.to_string(),
+                    guest_access: guest_access.to_string(),
+                },
+            )
+            .await?;
+        }
+
+        if let Some(encryption) = encryption {
+            let is_encrypted = self
+                .get_room_state(room_id, "m.room.encryption")
+                .await?
+                .map_or(false, |c| c.get("algorithm").is_some());
+            if encryption
+                && !is_encrypted
+                && self.plan(Action::EnableEncryption {
+                    room_id: room_id.to_string(),
+                })
+            {
+                self.send_state(
+                    &RoomId::try_from(room_id)?,
+                    "m.room.encryption",
+                    "",
+                    json!({ "algorithm": "m.megolm.v1.aes-sha2" }),
+                )
+                .await?;
+            } else if !encryption && is_encrypted {
+                warn!(
+                    "Room {} should not be encrypted, but encryption cannot be disabled",
+                    room_id
+                );
+            }
+        }
+
+        Ok(())
     }
 
-    pub async fn remove_user_from_room(&self, room_id: &str, user_id: &str) -> Result<()> {
-        todo!();
+    /// Publish the room to the room directory or remove it from there, and do the same with the
+    /// directory of an appservice network if given.
+    pub async fn ensure_room_directory(
+        &self,
+        room_id: &str,
+        directory: DirectoryVisibility,
+        network: Option<&str>,
+    ) -> Result<()> {
+        let visibility = match directory {
+            DirectoryVisibility::Public => Visibility::Public,
+            DirectoryVisibility::Private => Visibility::Private,
+        };
+
+        let current_visibility = if self.is_planned(room_id) {
+            Visibility::Private
+        } else {
+            let request = get_room_visibility::Request::new(&RoomId::try_from(room_id)?);
+            self.client.send(request, None).await?.visibility
+        };
+        if current_visibility != visibility
+            && self.plan(Action::SetDirectoryVisibility {
+                room_id: room_id.to_string(),
+                visibility: directory.to_string(),
+                network: None,
+            })
+        {
+            let room_id = RoomId::try_from(room_id)?;
+            let request = set_room_visibility::Request::new(&room_id, visibility);
+            self.client.send(request, None).await?;
+        }
+
+        if let Some(network) = network {
+            let appservice_token = self.appservice_token.as_ref().ok_or_else(|| {
+                eyre!(
+                    "Cannot publish room {} to the {} directory without an appservice token",
+                    room_id,
+                    network
+                )
+            })?;
+            // The visibility in appservice directories can't be read, so it is always set
+            if self.plan(Action::SetDirectoryVisibility {
+                room_id: room_id.to_string(),
+                visibility: directory.to_string(),
+                network: Some(network.to_string()),
+            }) {
+                self.send_raw(
+                    reqwest::Method::PUT,
+                    &[
+                        "_matrix",
+                        "client",
+                        "r0",
+                        "directory",
+                        "list",
+                        "appservice",
+                        network,
+                        room_id,
+                    ],
+                    appservice_token,
+                    &json!({ "visibility": directory.to_string() }),
+                )
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether a user with the given membership still has to be added to a room. Knocking users
+    /// still need to be let in, and invited ones to be joined when joins are forced.
+    pub fn needs_adding(&self, membership: Option<&str>) -> bool {
+        match membership {
+            None | Some("knock") => true,
+            Some("invite") => self.force_join,
+            _ => false,
+        }
+    }
+
+    /// Whether a user must never be kicked or demoted. We are always protected.
+    pub fn is_protected(&self, user_id: &str) -> bool {
+        user_id == self.mxid || self.protected_users.iter().any(|r| r.is_match(user_id))
+    }
+
+    /// Get the users that are either joined, invited or knocking in a room, with their membership.
+    pub async fn get_room_members(&self, room_id: &str) -> Result<BTreeMap<String, String>> {
+        if self.is_planned(room_id) {
+            return Ok(BTreeMap::new());
+        }
+
+        let room_id = RoomId::try_from(room_id)?;
+        let members_request = membership::get_member_events::Request::new(&room_id);
+        let members = self.client.send(members_request, None).await?.chunk;
+
+        let mut room_members = BTreeMap::new();
+        for raw_member in members {
+            let member: serde_json::Value = serde_json::from_str(raw_member.json().get())?;
+            let membership = member
+                .pointer("/content/membership")
+                .and_then(|m| m.as_str())
+                .unwrap_or_default();
+            if !["join", "invite", "knock"].contains(&membership) {
+                continue;
+            }
+            if let Some(user_id) = member.get("state_key").and_then(|k| k.as_str()) {
+                room_members.insert(user_id.to_string(), membership.to_string());
+            }
+        }
+
+        Ok(room_members)
     }
 
-    /* pub async fn create_space(
+    pub async fn add_user_to_room(&self, room_id: &str, user_id: &str) -> Result<()> {
+        if self.force_join {
+            if !self.plan(Action::ForceJoinUser {
+                room_id: room_id.to_string(),
+                user_id: user_id.to_string(),
+            }) {
+                return Ok(());
+            }
+            self.send_raw(
+                reqwest::Method::POST,
+                &["_synapse", "admin", "v1", "join", room_id],
+                &self.access_token,
+                &json!({ "user_id": user_id }),
+            )
+            .await?;
+        } else {
+            if !self.plan(Action::InviteUser {
+                room_id: room_id.to_string(),
+                user_id: user_id.to_string(),
+            }) {
+                return Ok(());
+            }
+            let room_id = RoomId::try_from(room_id)?;
+            let user_id = UserId::try_from(user_id)?;
+            let invite_request = membership::invite_user::Request::new(
+                &room_id,
+                invite_user::InvitationRecipient::UserId { user_id: &user_id },
+            );
+            let _response = self.client.send(invite_request, None).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Set the power level of all the users of a room at once. Users that are not in `users`
+    /// anymore are brought back to the default power level. Protected users, including ourselves,
+    /// are never demoted.
+    pub async fn ensure_users_powerlevel(
         &self,
-        name: String,
-        description: Option<String>,
-        avatar_url: Option<String>,
-    ) {
-        let space = create_room::Request {
-            creation_content: create_room::CreationContent {
-                federate: true,
-                predecessor: None,
-                room_type: Some("".to_string()),
-            },
-            initial_state: &vec![
-                AnyInitialStateEvent::RoomHistoryVisibility(InitialStateEvent {
-                    state_key: "".to_string(),
-                    content: HistoryVisibilityEventContent {
-                        history_visibility: HistoryVisibility::Invited,
-                    },
-                }),
-                AnyInitialStateEvent::RoomAvatar(InitialStateEvent {
-                    state_key: "".to_string(),
-                    content: AvatarEventContent {
-                        info: None,
-                        url: avatar_url.unwrap_or_default(),
-                    },
-                }),
-            ],
-            name: Some(&name),
-            preset: Some(create_room::RoomPreset::PrivateChat),
-            invite: &vec![],
-            invite_3pid: &vec![],
-            is_direct: false,
-            room_alias_name: None,
-            room_version: None,
-            topic: description.as_ref().map(|x| &**x),
-            visibility: Visibility::Private,
-            power_level_content_override: Some(
-                PowerLevelsEventContent {
-                    events_default: int!(100),
-                    ..Default::default()
+        room_id: &str,
+        users: &BTreeMap<String, i32>,
+    ) -> Result<()> {
+        // Rooms that would have been created in dry-run mode have the default power levels
+        let mut power_levels = if self.is_planned(room_id) {
+            let mut power_levels = json!({ "users": {} });
+            power_levels["users"][self.mxid.as_str()] = json!(100);
+            power_levels
+        } else {
+            self.get_state(&RoomId::try_from(room_id)?, "m.room.power_levels", "")
+                .await?
+                .ok_or_else(|| eyre!("Room {} has no m.room.power_levels event", room_id))?
+        };
+
+        let get_level = |pointer: &str, default: i64| {
+            power_levels
+                .pointer(pointer)
+                .and_then(|l| l.as_i64())
+                .unwrap_or(default)
+        };
+        let users_default = get_level("/users_default", 0);
+        let state_default = get_level("/state_default", 50);
+        let required_level = get_level("/events/m.room.power_levels", state_default);
+
+        let current_levels: BTreeMap<String, i64> = power_levels
+            .get("users")
+            .and_then(|u| u.as_object())
+            .map(|u| {
+                u.iter()
+                    .filter_map(|(user_id, level)| Some((user_id.clone(), level.as_i64()?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let own_level = current_levels
+            .get(&self.mxid)
+            .copied()
+            .unwrap_or(users_default);
+
+        if own_level < required_level {
+            warn!(
+                "Cannot change power levels in room {}, we have {} but {} is required",
+                room_id, own_level, required_level
+            );
+            return Ok(());
+        }
+
+        let mut levels = BTreeMap::new();
+        for (user_id, level) in users {
+            let mut level = i64::from(*level);
+            if level > own_level {
+                warn!(
+                    "Cannot give power level {} to {} in room {}, using {} instead",
+                    level, user_id, room_id, own_level
+                );
+                level = own_level;
+            }
+            if let Some(current_level) = current_levels.get(user_id) {
+                if self.is_protected(user_id) && *current_level > level {
+                    level = *current_level;
                 }
-                .into(),
-            ),
+            }
+            if level != users_default {
+                levels.insert(user_id.clone(), level);
+            }
+        }
+        // Protected users are never demoted, even if they are not declared
+        for (user_id, level) in &current_levels {
+            if self.is_protected(user_id) && !levels.contains_key(user_id) {
+                levels.insert(user_id.clone(), *level);
+            }
+        }
+        // We cannot change the power level of users that are at least as powerful as us
+        for (user_id, level) in &current_levels {
+            if *level >= own_level && levels.get(user_id) != Some(level) {
+                warn!(
+                    "Cannot change power level of {} in room {} as it is at least ours",
+                    user_id, room_id
+                );
+                levels.insert(user_id.clone(), *level);
+            }
+        }
+
+        if levels == current_levels
+            || !self.plan(Action::SetPowerLevels {
+                room_id: room_id.to_string(),
+                users: levels.clone(),
+            })
+        {
+            return Ok(());
+        }
+
+        power_levels["users"] = json!(levels);
+        self.send_state(
+            &RoomId::try_from(room_id)?,
+            "m.room.power_levels",
+            "",
+            power_levels,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_user_from_room(&self, room_id: &str, user_id: &str) -> Result<()> {
+        if !self.plan(Action::KickUser {
+            room_id: room_id.to_string(),
+            user_id: user_id.to_string(),
+        }) {
+            return Ok(());
+        }
+
+        let room_id = RoomId::try_from(room_id)?;
+        let user_id = UserId::try_from(user_id)?;
+        let mut kick_request = membership::kick_user::Request::new(&room_id, &user_id);
+        kick_request.reason = self.kick_reason.as_deref();
+        let _response = self.client.send(kick_request, None).await?;
+
+        Ok(())
+    }
+
+    /// Make `parent` the canonical parent of the room. Other parents, such as spaces the room was
+    /// linked into by hand, are kept but are not canonical anymore. Top-level spaces have no
+    /// parent in the tree, and their parents are left untouched.
+    async fn ensure_space_parent(&self, room_id: &RoomId, parent: Option<&str>) -> Result<()> {
+        let parent = match parent {
+            Some(parent) => parent,
+            None => return Ok(()),
         };
+        let current_parents = self.get_state_events(room_id, "m.space.parent").await?;
 
-        let room = match self.client.request(space).await {
-            Ok(a) => a,
-            _ => {
-                error!("Failed to create space");
-                return;
+        let is_linked = current_parents.iter().any(|(state_key, content)| {
+            state_key == parent && content.get("canonical") == Some(&json!(true))
+        });
+        if !is_linked
+            && self.plan(Action::SetParent {
+                room_id: room_id.to_string(),
+                parent: parent.to_string(),
+            })
+        {
+            self.send_state(
+                room_id,
+                "m.space.parent",
+                parent,
+                json!({ "via": [self.server_name], "canonical": true }),
+            )
+            .await?;
+        }
+
+        for (state_key, content) in current_parents {
+            if state_key != parent
+                && content.get("canonical") == Some(&json!(true))
+                && self.plan(Action::UnsetCanonicalParent {
+                    room_id: room_id.to_string(),
+                    parent: state_key.clone(),
+                })
+            {
+                let mut content = content;
+                content["canonical"] = json!(false);
+                self.send_state(room_id, "m.space.parent", &state_key, content)
+                    .await?;
             }
+        }
+
+        Ok(())
+    }
+
+    /// Make `children` the exact list of children of the space. Children that are not in the list
+    /// are only removed if `remove_others` is set.
+    pub async fn ensure_space_children(
+        &self,
+        space_id: &str,
+        children: &[String],
+        remove_others: bool,
+    ) -> Result<()> {
+        let current_children = if self.is_planned(space_id) {
+            vec![]
+        } else {
+            self.get_state_events(&RoomId::try_from(space_id)?, "m.space.child")
+                .await?
         };
-    } */
-    // pub async fn set_child(parent_id: RoomId, child_id: RoomId) {}
+
+        for child in children {
+            let is_linked = current_children
+                .iter()
+                .any(|(state_key, content)| state_key == child && content.get("via").is_some());
+            if !is_linked
+                && self.plan(Action::AddChild {
+                    space_id: space_id.to_string(),
+                    child: child.clone(),
+                })
+            {
+                self.send_state(
+                    &RoomId::try_from(space_id)?,
+                    "m.space.child",
+                    child,
+                    json!({ "via": [self.server_name] }),
+                )
+                .await?;
+            }
+        }
+
+        for (state_key, _) in &current_children {
+            if remove_others
+                && !children.contains(state_key)
+                && self.plan(Action::RemoveChild {
+                    space_id: space_id.to_string(),
+                    child: state_key.clone(),
+                })
+            {
+                self.send_state(
+                    &RoomId::try_from(space_id)?,
+                    "m.space.child",
+                    state_key,
+                    json!({}),
+                )
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
 }
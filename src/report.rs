@@ -0,0 +1,132 @@
+use crate::actions::Action;
+use color_eyre::eyre::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, Serialize)]
+pub struct RoomReport {
+    /// Path of the file or folder defining the room.
+    pub path: PathBuf,
+    pub id: Option<String>,
+    pub alias: Option<String>,
+    /// The room the metadata has been reconciled to.
+    pub room_id: Option<String>,
+    pub actions: Vec<Action>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SpaceReport {
+    #[serde(flatten)]
+    pub space: RoomReport,
+    pub rooms: Vec<RoomReport>,
+    pub children: Vec<SpaceReport>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SyncReport {
+    pub dry_run: bool,
+    pub users_created: Vec<String>,
+    /// Users that could not be created, with the reason why.
+    pub users_errors: BTreeMap<String, String>,
+    pub spaces: Vec<SpaceReport>,
+    pub error: Option<String>,
+}
+
+impl RoomReport {
+    pub fn new(path: &Path, id: Option<&String>, alias: Option<&String>) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            id: id.cloned(),
+            alias: alias.cloned(),
+            ..Default::default()
+        }
+    }
+
+    /// Record the outcome of the reconciliation of the room.
+    pub fn record(&mut self, result: &Result<String>, actions: Vec<Action>) {
+        self.actions.extend(actions);
+        match result {
+            Ok(room_id) => self.room_id = Some(room_id.clone()),
+            Err(e) => self.error = Some(format!("{:#}", e)),
+        }
+    }
+
+    /// Record an error that didn't stop the reconciliation of the room, such as its users not
+    /// being fetched, unless something worse happened.
+    pub fn record_error(&mut self, error: Option<&String>) {
+        if self.error.is_none() {
+            self.error = error.cloned();
+        }
+    }
+
+    fn errors(&self, errors: &mut Vec<String>) {
+        if let Some(error) = &self.error {
+            errors.push(format!(
+                "{}: {}",
+                self.id
+                    .as_ref()
+                    .or_else(|| self.alias.as_ref())
+                    .unwrap_or(&String::new()),
+                error
+            ));
+        }
+    }
+}
+
+impl SpaceReport {
+    fn errors(&self, errors: &mut Vec<String>) {
+        self.space.errors(errors);
+        for room in &self.rooms {
+            room.errors(errors);
+        }
+        for child in &self.children {
+            child.errors(errors);
+        }
+    }
+
+    fn rooms<'a>(&'a self, rooms: &mut Vec<&'a RoomReport>) {
+        rooms.push(&self.space);
+        rooms.extend(self.rooms.iter());
+        for child in &self.children {
+            child.rooms(rooms);
+        }
+    }
+}
+
+impl SyncReport {
+    /// Get all the errors that happened during the sync, the fatal one excepted.
+    pub fn errors(&self) -> Vec<String> {
+        let mut errors = self
+            .users_errors
+            .iter()
+            .map(|(user_id, error)| format!("{}: {}", user_id, error))
+            .collect();
+        for space in &self.spaces {
+            space.errors(&mut errors);
+        }
+        errors
+    }
+
+    /// Get the reports of all the rooms and spaces.
+    pub fn rooms(&self) -> Vec<&RoomReport> {
+        let mut rooms = vec![];
+        for space in &self.spaces {
+            space.rooms(&mut rooms);
+        }
+        rooms
+    }
+
+    /// Write the report as JSON to a file, or to stdout if the path is `-`.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let contents = serde_json::to_string_pretty(self)?;
+        if path == Path::new("-") {
+            println!("{}", contents);
+        } else {
+            std::fs::write(path, contents)?;
+        }
+
+        Ok(())
+    }
+}
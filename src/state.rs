@@ -0,0 +1,70 @@
+use crate::config::Config;
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Users and their power level in each room, by path of the room in the tree. Rooms whose users
+/// could not be fetched from LDAP have none.
+pub type Memberships = BTreeMap<PathBuf, Option<Vec<(String, i32)>>>;
+
+/// What was applied by the last successful sync.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct State {
+    pub commit: Option<String>,
+    /// Hash of the configuration affecting how the tree is reconciled, and of the version of
+    /// spacehub.
+    pub config: Option<String>,
+    /// Hash of the users of each room, by path of the room in the tree.
+    pub memberships: BTreeMap<PathBuf, Option<String>>,
+}
+
+impl State {
+    pub fn new(commit: Option<&str>, config: &Config, memberships: &Memberships) -> Result<Self> {
+        // Secrets don't change what the tree is reconciled to
+        let mut matrix = config.matrix.clone();
+        matrix.password.clear();
+        let mut ldap = config.ldap.clone();
+        ldap.bind_password = None;
+        let config = serde_json::to_vec(&(
+            env!("CARGO_PKG_VERSION"),
+            matrix,
+            ldap,
+            &config.default_names,
+        ))?;
+
+        let mut hashes = BTreeMap::new();
+        for (path, users) in memberships {
+            let hash = match users {
+                Some(users) => Some(hex::encode(Sha256::digest(&serde_json::to_vec(users)?))),
+                None => None,
+            };
+            hashes.insert(path.clone(), hash);
+        }
+
+        Ok(Self {
+            commit: commit.map(str::to_string),
+            config: Some(hex::encode(Sha256::digest(&config))),
+            memberships: hashes,
+        })
+    }
+
+    /// Load the state, or an empty one if there was no successful sync yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        // Write to a temporary file first so that the state is never left half written
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+}
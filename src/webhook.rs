@@ -0,0 +1,144 @@
+use crate::config::WebhookConfig;
+use color_eyre::eyre::Result;
+use hmac::{Hmac, Mac};
+use hyper::{
+    body::HttpBody,
+    header::{HeaderMap, CONTENT_LENGTH},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use sha2::Sha256;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tracing::*;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Bodies are read before their signature can be checked, so don't read more than the largest
+/// payload GitHub sends.
+const MAX_BODY_SIZE: usize = 25 * 1024 * 1024;
+
+/// Listen for push webhooks, and notify `trigger` when `branch` is pushed to.
+pub async fn serve(config: WebhookConfig, branch: String, trigger: Arc<Notify>) -> Result<()> {
+    let addr: SocketAddr = config.listen.parse()?;
+    let config = Arc::new(config);
+    let branch = Arc::new(branch);
+
+    let make_service = make_service_fn(move |_| {
+        let config = config.clone();
+        let branch = branch.clone();
+        let trigger = trigger.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle(request, config.clone(), branch.clone(), trigger.clone())
+            }))
+        }
+    });
+
+    info!("Listening for webhooks on {}", addr);
+    Server::try_bind(&addr)?.serve(make_service).await?;
+
+    Ok(())
+}
+
+fn respond(status: StatusCode, body: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
+
+/// Read the body of the request, up to `MAX_BODY_SIZE`.
+async fn read_body(request: Request<Body>) -> Result<Vec<u8>, Response<Body>> {
+    let too_large = || respond(StatusCode::PAYLOAD_TOO_LARGE, "Body too large");
+
+    let content_length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|l| l.to_str().ok())
+        .and_then(|l| l.parse::<usize>().ok());
+    if content_length.map_or(false, |l| l > MAX_BODY_SIZE) {
+        return Err(too_large());
+    }
+
+    // The length is not always given, nor to be trusted
+    let mut body = request.into_body();
+    let mut bytes = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| respond(StatusCode::BAD_REQUEST, "Could not read body"))?;
+        if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes)
+}
+
+async fn handle(
+    request: Request<Body>,
+    config: Arc<WebhookConfig>,
+    branch: Arc<String>,
+    trigger: Arc<Notify>,
+) -> Result<Response<Body>, Infallible> {
+    if request.method() != Method::POST || request.uri().path() != "/webhook" {
+        return Ok(respond(StatusCode::NOT_FOUND, "Not found"));
+    }
+
+    let headers = request.headers().clone();
+    let body = match read_body(request).await {
+        Ok(body) => body,
+        Err(response) => return Ok(response),
+    };
+
+    if !verify(&headers, &body, &config.secret) {
+        warn!("Rejected a webhook with an invalid signature");
+        return Ok(respond(StatusCode::UNAUTHORIZED, "Invalid signature"));
+    }
+
+    let payload: serde_json::Value = match serde_json::from_slice(&body) {
+        Ok(payload) => payload,
+        Err(_) => return Ok(respond(StatusCode::BAD_REQUEST, "Invalid payload")),
+    };
+    let git_ref = payload
+        .get("ref")
+        .and_then(|r| r.as_str())
+        .unwrap_or_default();
+    if git_ref != format!("refs/heads/{}", branch) {
+        info!("Ignoring a push to {}", git_ref);
+        return Ok(respond(StatusCode::OK, "Ignored"));
+    }
+
+    info!("Triggering a sync after a push to {}", git_ref);
+    trigger.notify_one();
+    Ok(respond(StatusCode::ACCEPTED, "Sync triggered"))
+}
+
+/// Check the signature of the webhook, as sent by GitHub, Gitea or GitLab.
+fn verify(headers: &HeaderMap, body: &[u8], secret: &str) -> bool {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let new_mac = || HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes any key");
+
+    // GitHub and Gitea sign the body
+    let signature = match header("X-Hub-Signature-256") {
+        Some(signature) => signature.strip_prefix("sha256="),
+        None => header("X-Gitea-Signature"),
+    };
+    if let Some(signature) = signature {
+        let mut mac = new_mac();
+        mac.update(body);
+        return hex::decode(signature).map_or(false, |s| mac.verify_slice(&s).is_ok());
+    }
+
+    // GitLab sends the secret as is, compare the MACs of both to compare them in constant time
+    if let Some(token) = header("X-Gitlab-Token") {
+        let mut expected = new_mac();
+        expected.update(secret.as_bytes());
+        let mut mac = new_mac();
+        mac.update(token.as_bytes());
+        return mac.verify_slice(&expected.finalize().into_bytes()).is_ok();
+    }
+
+    false
+}